@@ -0,0 +1,215 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context};
+use crate::misc_helper;
+use super::{check_key, is_temp_key, temp_key, Backend, ObjectInfo, ObjectWriter};
+
+/// Archive in a local dir; the default.
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf) -> LocalBackend {
+        return LocalBackend { root: root };
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        check_key(key)?;
+        return Ok(key.split('/').filter(|x| !x.is_empty()).fold(self.root.clone(), |path, part| path.join(part)));
+    }
+
+    fn create_parent(path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            misc_helper::create_dir_when_missing(parent)
+                .with_context(|| format!("cannot create dir {}", parent.to_string_lossy()))?;
+        }
+
+        return Ok(());
+    }
+
+    /// Dirs are implicit; remove the ones which became empty, but keep the top level ones.
+    fn remove_empty_parents(&self, path: &Path) {
+        let mut dir = path.parent();
+
+        while let Some(current) = dir {
+            if current.parent() == Some(self.root.as_path()) || !current.starts_with(&self.root) {
+                break;
+            }
+            if fs::remove_dir(current).is_err() {
+                break;
+            }
+            dir = current.parent();
+        }
+    }
+
+    /// Objects below `dir`; either the temporary ones or all others.
+    fn list_dir(&self, dir: &Path, key: &str, temp: bool, ret: &mut Vec<String>) -> anyhow::Result<()> {
+        for entry in fs::read_dir(dir).with_context(|| format!("cannot list {}", dir.to_string_lossy()))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let entry_key = super::join_key(key, &name);
+
+            if entry.file_type()?.is_dir() {
+                self.list_dir(&entry.path(), &entry_key, temp, ret)?;
+            } else if is_temp_key(&entry_key) == temp {
+                ret.push(entry_key);
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn list_keys(&self, prefix: &str, temp: bool) -> anyhow::Result<Vec<String>> {
+        let mut ret = Vec::new();
+        let dir = self.path(prefix)?;
+
+        if misc_helper::is_dir(&dir) {
+            self.list_dir(&dir, prefix.trim_end_matches('/'), temp, &mut ret)?;
+        }
+
+        return Ok(ret);
+    }
+}
+
+impl Backend for LocalBackend {
+    fn location(&self) -> String {
+        return self.root.to_string_lossy().to_string();
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        let path = self.path(key)?;
+        let file = File::open(&path)
+            .with_context(|| format!("cannot open {}", path.to_string_lossy()))?;
+        return Ok(Box::new(file));
+    }
+
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> anyhow::Result<Box<dyn Read + Send>> {
+        let path = self.path(key)?;
+        let mut file = File::open(&path)
+            .with_context(|| format!("cannot open {}", path.to_string_lossy()))?;
+        if file.metadata()?.len() < offset + length {
+            return Err(anyhow!("{} is truncated", path.to_string_lossy()));
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        return Ok(Box::new(file.take(length)));
+    }
+
+    fn put(&self, key: &str) -> anyhow::Result<Box<dyn ObjectWriter>> {
+        let path = self.path(key)?;
+        let temp_path = self.path(&temp_key(key))?;
+        LocalBackend::create_parent(&path)?;
+
+        let file = File::create_new(&temp_path)
+            .with_context(|| format!("cannot create {}", temp_path.to_string_lossy()))?;
+
+        return Ok(Box::new(LocalWriter {
+            writer: Some(BufWriter::new(file)),
+            temp_path: temp_path,
+            path: path,
+        }));
+    }
+
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        return self.list_keys(prefix, false);
+    }
+
+    fn list_temp(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        return self.list_keys(prefix, true);
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        fs::remove_file(&path)
+            .with_context(|| format!("cannot remove {}", path.to_string_lossy()))?;
+        self.remove_empty_parents(&path);
+        return Ok(());
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        return Ok(misc_helper::is_file(&self.path(key)?));
+    }
+
+    fn stat(&self, key: &str) -> anyhow::Result<ObjectInfo> {
+        let path = self.path(key)?;
+        let metadata = path.metadata()
+            .with_context(|| format!("cannot read metadata of {}", path.to_string_lossy()))?;
+
+        return Ok(ObjectInfo {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        });
+    }
+
+    fn lock(&self, key: &str, content: &[u8]) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        LocalBackend::create_parent(&path)?;
+        let mut file = File::create_new(&path)
+            .with_context(|| format!("cannot create {}", path.to_string_lossy()))?;
+        file.write_all(content)?;
+        return Ok(());
+    }
+
+    fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let from_path = self.path(from)?;
+        let to_path = self.path(to)?;
+        LocalBackend::create_parent(&to_path)?;
+
+        fs::rename(&from_path, &to_path)
+            .with_context(|| format!("cannot move {} to {}", from_path.to_string_lossy(), to_path.to_string_lossy()))?;
+        self.remove_empty_parents(&from_path);
+        return Ok(());
+    }
+}
+
+/// Writes into a temporary file which replaces the target on commit.
+struct LocalWriter {
+    writer: Option<BufWriter<File>>,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl Write for LocalWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        return self.writer.as_mut().expect("local writer already finished").write(buf);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.writer.as_mut().expect("local writer already finished").flush();
+    }
+}
+
+impl ObjectWriter for LocalWriter {
+    fn commit(mut self: Box<Self>) -> anyhow::Result<()> {
+        let writer = self.writer.take().expect("local writer already finished");
+
+        let file = writer.into_inner()
+            .map_err(|err| anyhow!("cannot write {}: {}", self.temp_path.to_string_lossy(), err.error()))?;
+        file.sync_all()
+            .with_context(|| format!("cannot sync {}", self.temp_path.to_string_lossy()))?;
+        drop(file);
+
+        fs::rename(&self.temp_path, &self.path)
+            .with_context(|| format!("cannot move {} to {}", self.temp_path.to_string_lossy(), self.path.to_string_lossy()))?;
+
+        //the rename is only durable once the dir is synced
+        #[cfg(unix)]
+        if let Some(parent) = self.path.parent() {
+            File::open(parent)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("cannot sync {}", parent.to_string_lossy()))?;
+        }
+
+        return Ok(());
+    }
+}
+
+impl Drop for LocalWriter {
+    fn drop(&mut self) {
+        //not committed
+        if self.writer.take().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
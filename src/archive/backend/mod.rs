@@ -0,0 +1,134 @@
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::{anyhow, bail, Context};
+
+mod local;
+mod s3;
+mod sftp;
+
+pub use local::LocalBackend;
+pub use s3::S3Backend;
+pub use sftp::SftpBackend;
+
+/// Prefix of objects which are still written; they are skipped by [`Backend::list`].
+pub const TEMP_PREFIX: &str = ".tmp-";
+
+pub struct ObjectInfo {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Writer of a new object.
+///
+/// The object becomes visible as a whole with [`ObjectWriter::commit`], which
+/// also makes sure it is durable. Dropping the writer discards the object.
+pub trait ObjectWriter: Write + Send {
+    fn commit(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+/// Storage of an archive.
+///
+/// Objects are addressed by keys relative to the archive root with `/` as
+/// separator, e.g. `content/<hash>`. Dirs are no objects of their own; they
+/// exist as long as there are objects below them.
+pub trait Backend: Send + Sync {
+    /// Where the archive is, for messages.
+    fn location(&self) -> String;
+
+    fn get(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send>>;
+
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> anyhow::Result<Box<dyn Read + Send>>;
+
+    /// Create or replace `key` once the writer is committed.
+    fn put(&self, key: &str) -> anyhow::Result<Box<dyn ObjectWriter>>;
+
+    /// All keys below the dir `prefix`, recursively.
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>>;
+
+    /// Temporary objects below `prefix` of writers which were never committed or discarded, e.g. after a crash.
+    fn list_temp(&self, prefix: &str) -> anyhow::Result<Vec<String>>;
+
+    fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool>;
+
+    fn stat(&self, key: &str) -> anyhow::Result<ObjectInfo>;
+
+    /// Create `key` with `content` unless it exists; fails when it does.
+    fn lock(&self, key: &str, content: &[u8]) -> anyhow::Result<()>;
+
+    fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        {
+            let mut reader = self.get(from)?;
+            let mut writer = self.put(to)?;
+            std::io::copy(&mut reader, &mut writer)
+                .with_context(|| format!("cannot copy {} to {}", from, to))?;
+            writer.commit()?;
+        }
+
+        return self.delete(from);
+    }
+
+    fn put_data(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        let mut writer = self.put(key)?;
+        writer.write_all(data)
+            .with_context(|| format!("cannot write {}", key))?;
+        return writer.commit();
+    }
+
+    fn get_data(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.get(key)?.read_to_end(&mut data)
+            .with_context(|| format!("cannot read {}", key))?;
+        return Ok(data);
+    }
+}
+
+/// Open the backend for `archive`: a local path, `file://path`,
+/// `sftp://[user@]host[:port]/path`, `s3://host[:port]/bucket/prefix`
+/// or `s3+http://...` for S3-compatible stores without TLS.
+pub fn open_backend(archive: &str) -> anyhow::Result<Arc<dyn Backend>> {
+    let Some((scheme, rest)) = archive.split_once("://") else {
+        return Ok(Arc::new(LocalBackend::new(PathBuf::from(archive))));
+    };
+
+    return Ok(match scheme {
+        "file" => Arc::new(LocalBackend::new(PathBuf::from(rest))),
+        "s3" => Arc::new(S3Backend::new("https", rest)?),
+        "s3+http" => Arc::new(S3Backend::new("http", rest)?),
+        "sftp" => Arc::new(SftpBackend::new(rest)?),
+        _ => bail!("unknown archive scheme {}", scheme),
+    });
+}
+
+/// Name of the temporary object a writer of `key` uses before it is renamed.
+pub fn temp_key(key: &str) -> String {
+    let random = rand::random::<u64>();
+    return match key.rsplit_once('/') {
+        Some((dir, name)) => format!("{}/{}{:016x}-{}", dir, TEMP_PREFIX, random, name),
+        None => format!("{}{:016x}-{}", TEMP_PREFIX, random, key),
+    };
+}
+
+pub fn is_temp_key(key: &str) -> bool {
+    return key.rsplit('/').next().is_some_and(|name| name.starts_with(TEMP_PREFIX));
+}
+
+/// Key of `name` inside the dir `prefix`.
+pub fn join_key(prefix: &str, name: &str) -> String {
+    return match prefix.is_empty() {
+        true => name.to_owned(),
+        false => format!("{}/{}", prefix.trim_end_matches('/'), name),
+    };
+}
+
+/// Reject keys which would leave the archive root.
+fn check_key(key: &str) -> anyhow::Result<()> {
+    if key.split('/').any(|part| part == ".." || part == ".") || key.starts_with('/') {
+        return Err(anyhow!("invalid key {}", key));
+    }
+
+    return Ok(());
+}
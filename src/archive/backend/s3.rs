@@ -0,0 +1,312 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use crate::misc_helper;
+use super::{check_key, is_temp_key, join_key, Backend, ObjectInfo, ObjectWriter};
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Archive in a bucket of an S3-compatible object store, addressed path style.
+///
+/// Credentials are taken from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`,
+/// the region from `AWS_REGION` (default `us-east-1`).
+pub struct S3Backend {
+    client: Arc<S3Client>,
+    prefix: String,
+}
+
+struct S3Client {
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    agent: ureq::Agent,
+}
+
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut ret = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => ret.push(byte as char),
+            b'/' if keep_slash => ret.push('/'),
+            _ => ret.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    return ret;
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes any key size");
+    mac.update(data.as_bytes());
+    return mac.finalize().into_bytes().to_vec();
+}
+
+/// Text of the first `<tag>` in `xml`, starting at `from`; returns it with the position after it.
+fn xml_value<'a>(xml: &'a str, tag: &str, from: usize) -> Option<(&'a str, usize)> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml[from..].find(&open)? + from + open.len();
+    let end = xml[start..].find(&close)? + start;
+    return Some((&xml[start..end], end + close.len()));
+}
+
+fn xml_unescape(value: &str) -> String {
+    return value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+}
+
+impl S3Backend {
+    /// `location` is `host[:port]/bucket[/prefix]`.
+    pub fn new(scheme: &str, location: &str) -> anyhow::Result<S3Backend> {
+        let mut parts = location.splitn(3, '/');
+        let host = parts.next().unwrap_or_default();
+        let bucket = parts.next().unwrap_or_default();
+        let prefix = parts.next().unwrap_or_default().trim_matches('/');
+        if host.is_empty() || bucket.is_empty() {
+            bail!("S3 archive needs a host and a bucket: s3://host/bucket/prefix");
+        }
+
+        return Ok(S3Backend {
+            client: Arc::new(S3Client {
+                endpoint: format!("{}://{}", scheme, host),
+                host: host.to_owned(),
+                bucket: bucket.to_owned(),
+                region: std::env::var("AWS_REGION").unwrap_or("us-east-1".to_owned()),
+                access_key: std::env::var("AWS_ACCESS_KEY_ID")
+                    .with_context(|| "AWS_ACCESS_KEY_ID is not set")?,
+                secret_key: std::env::var("AWS_SECRET_ACCESS_KEY")
+                    .with_context(|| "AWS_SECRET_ACCESS_KEY is not set")?,
+                agent: ureq::AgentBuilder::new().build(),
+            }),
+            prefix: prefix.to_owned(),
+        });
+    }
+
+    fn object_key(&self, key: &str) -> anyhow::Result<String> {
+        check_key(key)?;
+        return Ok(join_key(&self.prefix, key));
+    }
+
+    fn head(&self, key: &str) -> anyhow::Result<Option<ureq::Response>> {
+        return match self.client.request("HEAD", &self.object_key(key)?, &[]).call() {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("cannot stat {}", key)),
+        };
+    }
+}
+
+impl S3Client {
+    /// A request signed with AWS signature version 4; `query` must be sorted.
+    fn request(&self, method: &str, object_key: &str, query: &[(&str, &str)]) -> ureq::Request {
+        let now: DateTime<Utc> = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let path = match object_key.is_empty() {
+            true => format!("/{}", uri_encode(&self.bucket, false)),
+            false => format!("/{}/{}", uri_encode(&self.bucket, false), uri_encode(object_key, true)),
+        };
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, false), uri_encode(value, false)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, path, query, self.host, UNSIGNED_PAYLOAD, amz_date, UNSIGNED_PAYLOAD
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = ["s3", "aws4_request"].iter().fold(
+            hmac_sha256(&hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), &date), &self.region),
+            |key, part| hmac_sha256(&key, part),
+        );
+        let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign));
+
+        let url = match query.is_empty() {
+            true => format!("{}{}", self.endpoint, path),
+            false => format!("{}{}?{}", self.endpoint, path, query),
+        };
+
+        return self.agent.request(method, &url)
+            .set("x-amz-date", &amz_date)
+            .set("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .set("Authorization", &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                self.access_key, scope, signature
+            ));
+    }
+}
+
+impl Backend for S3Backend {
+    fn location(&self) -> String {
+        return format!("{}/{}/{}", self.client.endpoint, self.client.bucket, self.prefix);
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        let response = self.client.request("GET", &self.object_key(key)?, &[]).call()
+            .with_context(|| format!("cannot get {}", key))?;
+        return Ok(Box::new(response.into_reader()));
+    }
+
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> anyhow::Result<Box<dyn Read + Send>> {
+        if length == 0 {
+            return Ok(Box::new(std::io::empty()));
+        }
+
+        let response = self.client.request("GET", &self.object_key(key)?, &[])
+            .set("Range", &format!("bytes={}-{}", offset, offset + length - 1))
+            .call()
+            .with_context(|| format!("cannot get {}", key))?;
+        if response.status() != 206 {
+            bail!("store ignored the range request for {}", key);
+        }
+        return Ok(Box::new(response.into_reader().take(length)));
+    }
+
+    fn put(&self, key: &str) -> anyhow::Result<Box<dyn ObjectWriter>> {
+        //objects need their size up front; so buffer them in a temporary file
+        let file = misc_helper::temp_file()?;
+
+        return Ok(Box::new(S3Writer {
+            client: self.client.clone(),
+            object_key: self.object_key(key)?,
+            writer: BufWriter::new(file),
+        }));
+    }
+
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let mut ret = Vec::new();
+        let object_prefix = format!("{}/", self.object_key(prefix.trim_end_matches('/'))?);
+        let strip = match self.prefix.is_empty() {
+            true => 0,
+            false => self.prefix.len() + 1,
+        };
+        let mut token: Option<String> = None;
+
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", object_prefix.as_str())];
+            if let Some(token) = &token {
+                query.insert(0, ("continuation-token", token.as_str()));
+            }
+
+            let xml = self.client.request("GET", "", &query).call()
+                .with_context(|| format!("cannot list {}", prefix))?
+                .into_string()?;
+
+            let mut pos = 0;
+            while let Some((contents, next)) = xml_value(&xml, "Contents", pos) {
+                if let Some((key, _)) = xml_value(contents, "Key", 0) {
+                    let key = xml_unescape(key);
+                    if !is_temp_key(&key) && key.len() > strip {
+                        ret.push(key[strip..].to_owned());
+                    }
+                }
+                pos = next;
+            }
+
+            token = match xml_value(&xml, "IsTruncated", 0) {
+                Some(("true", _)) => xml_value(&xml, "NextContinuationToken", 0).map(|(x, _)| xml_unescape(x)),
+                _ => None,
+            };
+            if token.is_none() {
+                break;
+            }
+        }
+
+        return Ok(ret);
+    }
+
+    /// Objects are only uploaded on commit, so there are never temporary ones.
+    fn list_temp(&self, _prefix: &str) -> anyhow::Result<Vec<String>> {
+        return Ok(Vec::new());
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.client.request("DELETE", &self.object_key(key)?, &[]).call()
+            .with_context(|| format!("cannot delete {}", key))?;
+        return Ok(());
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        return Ok(self.head(key)?.is_some());
+    }
+
+    fn stat(&self, key: &str) -> anyhow::Result<ObjectInfo> {
+        let response = self.head(key)?.ok_or(anyhow!("{} does not exist", key))?;
+
+        let size = response.header("Content-Length")
+            .and_then(|x| x.parse().ok())
+            .ok_or(anyhow!("no size for {}", key))?;
+        let modified = response.header("Last-Modified")
+            .and_then(|x| DateTime::parse_from_rfc2822(x).ok())
+            .map(SystemTime::from)
+            .ok_or(anyhow!("no modification time for {}", key))?;
+
+        return Ok(ObjectInfo { size: size, modified: modified });
+    }
+
+    fn lock(&self, key: &str, content: &[u8]) -> anyhow::Result<()> {
+        //conditional write; fails with 412 when the object exists
+        let result = self.client.request("PUT", &self.object_key(key)?, &[])
+            .set("If-None-Match", "*")
+            .send_bytes(content);
+
+        return match result {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(412, _)) => Err(anyhow!("{} exists already", key)),
+            Err(err) => Err(err).with_context(|| format!("cannot create {}", key)),
+        };
+    }
+}
+
+/// Uploads the buffered object on commit.
+struct S3Writer {
+    client: Arc<S3Client>,
+    object_key: String,
+    writer: BufWriter<File>,
+}
+
+impl Write for S3Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        return self.writer.write(buf);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
+impl ObjectWriter for S3Writer {
+    fn commit(self: Box<Self>) -> anyhow::Result<()> {
+        let mut file = self.writer.into_inner()
+            .map_err(|err| anyhow!("cannot buffer {}: {}", self.object_key, err.error()))?;
+        let size = file.stream_position()?;
+        file.seek(SeekFrom::Start(0))?;
+
+        //a PUT replaces the object as a whole and is durable once it succeeded
+        self.client.request("PUT", &self.object_key, &[])
+            .set("Content-Length", &size.to_string())
+            .send(file)
+            .with_context(|| format!("cannot upload {}", self.object_key))?;
+
+        return Ok(());
+    }
+}
@@ -0,0 +1,279 @@
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::{anyhow, bail, Context};
+use ssh2::{CheckResult, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use super::{check_key, is_temp_key, join_key, temp_key, Backend, ObjectInfo, ObjectWriter};
+
+const SSH_PORT: u16 = 22;
+
+/// Archive in a dir of an SSH server.
+///
+/// The host key must be in `~/.ssh/known_hosts`. Authentication uses the ssh
+/// agent, then `BACKUPTOOL_SSH_KEY` or the default key files.
+pub struct SftpBackend {
+    location: String,
+    root: PathBuf,
+    sftp: Arc<Sftp>,
+
+    //the sftp channel needs the session to stay alive
+    #[allow(dead_code)]
+    session: Session,
+}
+
+fn home_dir() -> Option<PathBuf> {
+    return std::env::var_os("HOME").map(PathBuf::from);
+}
+
+fn check_host_key(session: &Session, host: &str, port: u16) -> anyhow::Result<()> {
+    let (key, _) = session.host_key().ok_or(anyhow!("server sent no host key"))?;
+    let mut known_hosts = session.known_hosts()?;
+    let known_hosts_file = home_dir()
+        .ok_or(anyhow!("no home dir for known_hosts"))?
+        .join(".ssh")
+        .join("known_hosts");
+    known_hosts.read_file(&known_hosts_file, KnownHostFileKind::OpenSSH)
+        .with_context(|| format!("cannot read {}", known_hosts_file.to_string_lossy()))?;
+
+    return match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => Err(anyhow!("host key of {} is not in known_hosts", host)),
+        CheckResult::Mismatch => Err(anyhow!("host key of {} does not match known_hosts", host)),
+        CheckResult::Failure => Err(anyhow!("cannot check host key of {}", host)),
+    };
+}
+
+fn authenticate(session: &Session, user: &str) -> anyhow::Result<()> {
+    if session.userauth_agent(user).is_ok() && session.authenticated() {
+        return Ok(());
+    }
+
+    let key_files: Vec<PathBuf> = match std::env::var_os("BACKUPTOOL_SSH_KEY") {
+        Some(key_file) => vec![PathBuf::from(key_file)],
+        None => ["id_ed25519", "id_ecdsa", "id_rsa"]
+            .iter()
+            .filter_map(|name| home_dir().map(|x| x.join(".ssh").join(name)))
+            .collect(),
+    };
+
+    for key_file in key_files {
+        if key_file.is_file() && session.userauth_pubkey_file(user, None, &key_file, None).is_ok() {
+            return Ok(());
+        }
+    }
+
+    bail!("cannot authenticate as {}", user);
+}
+
+impl SftpBackend {
+    /// `location` is `[user@]host[:port]/path`; the path is relative to the login dir.
+    pub fn new(location: &str) -> anyhow::Result<SftpBackend> {
+        let (authority, path) = location.split_once('/').unwrap_or((location, ""));
+        let (user, host_port) = match authority.split_once('@') {
+            Some((user, host_port)) => (user.to_owned(), host_port),
+            None => (std::env::var("USER").with_context(|| "no user in the archive url and USER is not set")?, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().with_context(|| format!("invalid port {}", port))?),
+            None => (host_port, SSH_PORT),
+        };
+
+        let tcp = TcpStream::connect((host, port))
+            .with_context(|| format!("cannot connect to {}:{}", host, port))?;
+        tcp.set_read_timeout(Some(Duration::from_secs(300)))?;
+
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake().with_context(|| format!("ssh handshake with {} failed", host))?;
+        check_host_key(&session, host, port)?;
+        authenticate(&session, &user)?;
+
+        let sftp = session.sftp().with_context(|| format!("cannot start sftp on {}", host))?;
+
+        return Ok(SftpBackend {
+            location: format!("sftp://{}@{}:{}/{}", user, host, port, path),
+            root: PathBuf::from(path),
+            sftp: Arc::new(sftp),
+            session: session,
+        });
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        check_key(key)?;
+        return Ok(self.root.join(key));
+    }
+
+    fn create_parent(&self, path: &Path) -> anyhow::Result<()> {
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+
+        let mut current = PathBuf::new();
+        for part in parent.iter() {
+            current.push(part);
+            if self.sftp.stat(&current).is_err() {
+                self.sftp.mkdir(&current, 0o755)
+                    .with_context(|| format!("cannot create dir {}", current.to_string_lossy()))?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Objects below `dir`; either the temporary ones or all others.
+    fn list_dir(&self, dir: &Path, key: &str, temp: bool, ret: &mut Vec<String>) -> anyhow::Result<()> {
+        for (path, stat) in self.sftp.readdir(dir).with_context(|| format!("cannot list {}", dir.to_string_lossy()))? {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let entry_key = join_key(key, &name);
+
+            if stat.is_dir() {
+                self.list_dir(&path, &entry_key, temp, ret)?;
+            } else if is_temp_key(&entry_key) == temp {
+                ret.push(entry_key);
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn list_keys(&self, prefix: &str, temp: bool) -> anyhow::Result<Vec<String>> {
+        let mut ret = Vec::new();
+        let dir = self.path(prefix)?;
+
+        if self.sftp.stat(&dir).is_ok_and(|x| x.is_dir()) {
+            self.list_dir(&dir, prefix.trim_end_matches('/'), temp, &mut ret)?;
+        }
+
+        return Ok(ret);
+    }
+}
+
+impl Backend for SftpBackend {
+    fn location(&self) -> String {
+        return self.location.clone();
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        let file = self.sftp.open(&self.path(key)?)
+            .with_context(|| format!("cannot open {}", key))?;
+        return Ok(Box::new(file));
+    }
+
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> anyhow::Result<Box<dyn Read + Send>> {
+        let mut file = self.sftp.open(&self.path(key)?)
+            .with_context(|| format!("cannot open {}", key))?;
+        if file.stat()?.size.unwrap_or(0) < offset + length {
+            bail!("{} is truncated", key);
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        return Ok(Box::new(file.take(length)));
+    }
+
+    fn put(&self, key: &str) -> anyhow::Result<Box<dyn ObjectWriter>> {
+        let path = self.path(key)?;
+        let temp_path = self.path(&temp_key(key))?;
+        self.create_parent(&path)?;
+
+        let file = self.sftp.open_mode(&temp_path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE, 0o644, OpenType::File)
+            .with_context(|| format!("cannot create {}", temp_path.to_string_lossy()))?;
+
+        return Ok(Box::new(SftpWriter {
+            sftp: self.sftp.clone(),
+            writer: Some(BufWriter::new(file)),
+            temp_path: temp_path,
+            path: path,
+        }));
+    }
+
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        return self.list_keys(prefix, false);
+    }
+
+    fn list_temp(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        return self.list_keys(prefix, true);
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.sftp.unlink(&self.path(key)?)
+            .with_context(|| format!("cannot remove {}", key))?;
+        return Ok(());
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        return Ok(self.sftp.stat(&self.path(key)?).is_ok_and(|x| x.is_file()));
+    }
+
+    fn stat(&self, key: &str) -> anyhow::Result<ObjectInfo> {
+        let stat = self.sftp.stat(&self.path(key)?)
+            .with_context(|| format!("cannot read metadata of {}", key))?;
+
+        return Ok(ObjectInfo {
+            size: stat.size.unwrap_or(0),
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(stat.mtime.unwrap_or(0)),
+        });
+    }
+
+    fn lock(&self, key: &str, content: &[u8]) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        self.create_parent(&path)?;
+        let mut file = self.sftp.open_mode(&path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE, 0o644, OpenType::File)
+            .with_context(|| format!("cannot create {}", key))?;
+        file.write_all(content)?;
+        return Ok(());
+    }
+
+    fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let to_path = self.path(to)?;
+        self.create_parent(&to_path)?;
+        self.sftp.rename(&self.path(from)?, &to_path, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE))
+            .with_context(|| format!("cannot move {} to {}", from, to))?;
+        return Ok(());
+    }
+}
+
+/// Writes into a temporary file which replaces the target on commit.
+struct SftpWriter {
+    sftp: Arc<Sftp>,
+    writer: Option<BufWriter<ssh2::File>>,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl Write for SftpWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        return self.writer.as_mut().expect("sftp writer already finished").write(buf);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.writer.as_mut().expect("sftp writer already finished").flush();
+    }
+}
+
+impl ObjectWriter for SftpWriter {
+    fn commit(mut self: Box<Self>) -> anyhow::Result<()> {
+        let writer = self.writer.take().expect("sftp writer already finished");
+
+        let mut file = writer.into_inner()
+            .map_err(|err| anyhow!("cannot write {}: {}", self.temp_path.to_string_lossy(), err.error()))?;
+        //fsync is an extension of OpenSSH; other servers may not have it
+        let _ = file.fsync();
+        drop(file);
+
+        self.sftp.rename(&self.temp_path, &self.path, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE))
+            .with_context(|| format!("cannot move {} to {}", self.temp_path.to_string_lossy(), self.path.to_string_lossy()))?;
+
+        return Ok(());
+    }
+}
+
+impl Drop for SftpWriter {
+    fn drop(&mut self) {
+        //not committed
+        if let Some(writer) = self.writer.take() {
+            drop(writer);
+            let _ = self.sftp.unlink(&self.temp_path);
+        }
+    }
+}
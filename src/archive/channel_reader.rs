@@ -1,9 +1,10 @@
-use std::path::Path;
-use std::{collections::VecDeque, fs::File, iter::Peekable, path::PathBuf};
+use std::path::{Component, Path};
+use std::{collections::VecDeque, fs::File, io::Read, iter::Peekable, path::PathBuf};
 use anyhow::{anyhow, bail, Context};
 use crate::meta_format;
 use crate::checksum::{self, HashResult};
 use super::defs;
+use super::file_meta::{FileMeta, FileSignature, SpecialKind};
 use super::session::{self, GetSession};
 use super::session::{BackupSession, ToSession};
 
@@ -20,16 +21,43 @@ pub struct ChannelReader {
     #[allow(dead_code)]
     channel: String,
 
-    reader: Peekable<meta_format::Reader<File>>,
+    reader: Peekable<meta_format::Reader<Box<dyn Read>>>,
     seen_entries: VecDeque<meta_format::ReaderEntry>,
     unseen_entries: Option<meta_format::ReaderEntry>,
 }
 
-//make an variant with all members optional
+#[derive(Debug)]
+pub enum ChannelItemKind {
+    /// `checksum` is the hash of the whole file. `contents` make up the file in
+    /// order; a single one named by `checksum` unless the file is `chunked`.
+    /// `signature` is missing in revisions written before it was recorded.
+    File {
+        checksum: HashResult,
+        contents: Vec<HashResult>,
+        chunked: bool,
+        signature: Option<FileSignature>,
+    },
+    Dir,
+    Symlink { target: PathBuf },
+    /// `target` is relative to the restore root like `relative_path`
+    Hardlink { target: PathBuf },
+    Special { kind: SpecialKind, rdev: u64 },
+}
+
 #[derive(Debug)]
 pub struct ChannelReaderItem {
     pub relative_path: PathBuf,
-    pub content_path: PathBuf,
+    pub kind: ChannelItemKind,
+    pub meta: FileMeta,
+}
+
+impl ChannelReaderItem {
+    pub fn contents(&self) -> &[HashResult] {
+        return match &self.kind {
+            ChannelItemKind::File { contents, .. } => contents,
+            _ => &[],
+        };
+    }
 }
 
 impl ChannelReader {
@@ -39,76 +67,163 @@ impl ChannelReader {
     ) -> anyhow::Result<ChannelReader> {
         let entry = match opt.entry {
             Some(entry) => entry.clone(),
-            None => defs::channel_rev_last(&backup_session.archive_dir, &opt.channel)?
-                .to_string_lossy()
-                .to_string(),
+            None => defs::key_name(&defs::channel_rev_last(backup_session.backend().as_ref(), &opt.channel)?).to_owned(),
         };
 
-        let file = File::open(defs::channel_file(
-            &backup_session.archive_dir,
-            &opt.channel,
-            &entry,
-        ))?;
+        let file = backup_session.open_revision(&defs::channel_file(&opt.channel, &entry))?;
 
-        return Ok(ChannelReader {
+        return Ok(ChannelReader::from_revision(backup_session, &opt.channel, file));
+    }
+
+    /// Reader of a revision of `channel` which is already opened, e.g. to keep
+    /// the session when the revision cannot be opened.
+    pub fn from_revision(backup_session: BackupSession, channel: &str, file: Box<dyn Read>) -> ChannelReader {
+        return ChannelReader {
             session: backup_session,
-            channel: opt.channel,
+            channel: channel.to_owned(),
             reader: meta_format::Reader::new(file).peekable(),
             seen_entries: VecDeque::new(),
             unseen_entries: None,
-        });
+        };
     }
 
-    fn finish(&mut self) -> anyhow::Result<ChannelReaderItem> {
+    fn finish(&mut self) -> anyhow::Result<Option<ChannelReaderItem>> {
         let seen = std::mem::replace(&mut self.seen_entries, VecDeque::new());
 
         if let Some(entry) = self.unseen_entries.take() {
             self.seen_entries.push_back(entry);
         }
 
+        let mut item_key: Option<String> = None;
         let mut relative_path: Option<PathBuf> = None;
         let mut checksum: Option<HashResult> = None;
+        let mut target: Option<PathBuf> = None;
+        let mut special_kind: Option<SpecialKind> = None;
+        let mut rdev = 0u64;
+        let mut size: Option<u64> = None;
+        let mut inode: Option<u64> = None;
+        let mut chunks: Vec<HashResult> = Vec::new();
+        let mut meta = FileMeta::default();
 
         for entry in seen {
-            if entry.key == defs::keys::FILE {
+            if defs::keys::is_item(&entry.key) {
                 relative_path = Some(Path::new(&entry.value).into());
+                item_key = Some(entry.key);
             } else if entry.key == defs::keys::HASH {
-                checksum = Some(HashResult::from_hex_string(&entry.value)?);
+                checksum = Some(HashResult::parse(&entry.value)?);
+            } else if entry.key == defs::keys::TARGET {
+                target = Some(Path::new(&entry.value).into());
+            } else if entry.key == defs::keys::TYPE {
+                special_kind = Some(SpecialKind::from_str(&entry.value)?);
+            } else if entry.key == defs::keys::RDEV {
+                rdev = entry.value.parse()?;
+            } else if entry.key == defs::keys::CHUNK {
+                chunks.push(HashResult::parse(&entry.value)?);
+            } else if entry.key == defs::keys::SIZE {
+                size = Some(entry.value.parse()?);
+            } else if entry.key == defs::keys::INODE {
+                inode = Some(entry.value.parse()?);
+            } else {
+                meta.parse_entry(&entry.key, &entry.value)?;
             }
         }
 
-        let item = ChannelReaderItem {
-            relative_path: relative_path.ok_or(anyhow!("relative path missing"))?,
-            content_path: PathBuf::from({
+        let item_key = item_key.ok_or(anyhow!("relative path missing"))?;
+        let relative_path = relative_path.ok_or(anyhow!("relative path missing"))?;
+
+        //older revisions recorded dirs with the absolute source path; they cannot be mapped
+        if item_key == defs::keys::DIR && relative_path.is_absolute() {
+            return Ok(None);
+        }
+
+        if !is_relative_normal(&relative_path) {
+            bail!("invalid relative path {}", relative_path.to_string_lossy());
+        }
+
+        let kind = match item_key.as_str() {
+            defs::keys::DIR => ChannelItemKind::Dir,
+            defs::keys::SYMLINK => ChannelItemKind::Symlink {
+                target: target.ok_or(anyhow!("symlink target is missing"))?,
+            },
+            defs::keys::HARDLINK => {
+                let target = target.ok_or(anyhow!("hardlink target is missing"))?;
+                if !is_relative_normal(&target) {
+                    bail!("invalid hardlink target {}", target.to_string_lossy());
+                }
+                ChannelItemKind::Hardlink { target: target }
+            }
+            defs::keys::SPECIAL => ChannelItemKind::Special {
+                kind: special_kind.ok_or(anyhow!("special file type is missing"))?,
+                rdev: rdev,
+            },
+            _ => {
                 let checksum = checksum.ok_or(anyhow!("checksum is missing"))?;
-                defs::content_file(self.session.get_archive_dir(), &checksum.data())
-            }),
+                let signature = match (size, meta.mtime, inode) {
+                    (Some(size), Some(mtime), Some(inode)) => Some(FileSignature {
+                        size: size,
+                        mtime: mtime,
+                        inode: inode,
+                    }),
+                    _ => None,
+                };
+                let chunked = !chunks.is_empty();
+                if !chunked {
+                    chunks.push(checksum.clone());
+                }
+                ChannelItemKind::File {
+                    checksum: checksum,
+                    contents: chunks,
+                    chunked: chunked,
+                    signature: signature,
+                }
+            }
+        };
+
+        let item = ChannelReaderItem {
+            relative_path: relative_path,
+            kind: kind,
+            meta: meta,
         };
 
-        return Ok(item);
+        return Ok(Some(item));
     }
 }
 
+fn is_relative_normal(path: &Path) -> bool {
+    return path.components().all(|x| matches!(x, Component::Normal(_)));
+}
+
 impl Iterator for ChannelReader {
     type Item = anyhow::Result<ChannelReaderItem>;
 
     fn next(&mut self) -> Option<anyhow::Result<ChannelReaderItem>> {
-        for entry in &mut self.reader {
-            let is_file = entry.key == defs::keys::FILE;
-            let entry_count = self.seen_entries.len();
-
-            if is_file && entry_count > 0 {
-                self.unseen_entries = Some(entry.clone());
-                return Some(self.finish());
-            } else if is_file && entry_count == 0 || !is_file {
-                self.seen_entries.push_back(entry.clone());
+        loop {
+            for entry in &mut self.reader {
+                let is_record = defs::keys::is_item(&entry.key);
+                let entry_count = self.seen_entries.len();
+
+                if is_record && entry_count > 0 {
+                    self.unseen_entries = Some(entry.clone());
+                    break;
+                } else {
+                    self.seen_entries.push_back(entry.clone());
+                }
             }
-        }
 
-        if self.seen_entries.is_empty() {
-            return None;
-        } else {
-            return Some(self.finish());
+            if self.seen_entries.is_empty() {
+                return None;
+            }
+
+            let item_path = self.seen_entries.iter()
+                .find(|x| defs::keys::is_item(&x.key))
+                .map(|x| x.value.clone());
+            let item = self.finish().with_context(|| match &item_path {
+                Some(path) => format!("cannot read entry {} of the revision", path),
+                None => format!("cannot read an entry without path of the revision"),
+            });
+            if let Some(item) = item.transpose() {
+                return Some(item);
+            }
         }
     }
 }
@@ -1,34 +1,38 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::path::Path;
 use std::{io::Write, path::PathBuf};
 use anyhow::{anyhow, bail, Context};
 use crate::checksum::HashResult;
 use crate::{checksum, meta_format, misc_helper};
+use super::backend::ObjectWriter;
 use super::defs;
+use super::file_meta::{FileMeta, FileSignature, SpecialKind};
 use super::session::{BackupSession, GetSession, ToSession};
 
 
 pub struct ChannelWriter {
     session: BackupSession,
-    writer: meta_format::Writer<Box<dyn Write + Send>>,
+    writer: meta_format::Writer<Box<dyn ObjectWriter>>,
+    hardlinks: HashMap<(u64, u64), PathBuf>,
 }
 
 pub enum ChannelWriterAdd {
-    HashFile(PathBuf),
+    /// key of the content file to write
+    HashFile(String),
+    /// the content goes into a pack; see [`ChannelWriter::add_packed_content`]
+    PackFile,
     AlreadyExist,
 }
 
 impl<'a> ChannelWriter {
     pub fn new(backup_session: BackupSession, channel: &str) -> anyhow::Result<ChannelWriter> {
-        let archive_dir = &backup_session.archive_dir;
-
-        misc_helper::create_dir_when_missing(&defs::channel_dir(archive_dir, channel))?;
-        misc_helper::create_dir_when_missing(&defs::content_dir(archive_dir))?;
-        let file = Box::new(File::create(defs::next_channel_file(archive_dir, channel)?)?);
+        let file = backup_session.create_revision(&defs::next_channel_file(channel))?;
 
         return Ok(ChannelWriter {
             session: backup_session,
             writer: meta_format::Writer::new(file),
+            hardlinks: HashMap::new(),
         });
     }
 
@@ -36,8 +40,67 @@ impl<'a> ChannelWriter {
         &mut self,
         path: &Path,
         checksum: &HashResult,
+        signature: &FileSignature,
+        meta: &FileMeta,
     ) -> anyhow::Result<ChannelWriterAdd> {
         //meta data
+        self.add_file_entry(path, checksum, &[], signature, meta)?;
+
+        //file
+        let store = self.session.content_store();
+
+        if store.contains(checksum)? {
+            return Ok(ChannelWriterAdd::AlreadyExist);
+        }
+
+        if store.wants_pack(signature.size) {
+            return Ok(ChannelWriterAdd::PackFile);
+        }
+
+        return Ok(ChannelWriterAdd::HashFile(store.loose_path(checksum)));
+    }
+
+    /// Append encoded content to the current pack; false when another file stored it in between.
+    pub fn add_packed_content(&mut self, checksum: &HashResult, blob: &[u8]) -> anyhow::Result<bool> {
+        return self.session.content_store_mut().add_packed(checksum, blob);
+    }
+
+    /// Finish the current pack, so the content is found by the next session.
+    pub fn flush_content(&mut self) -> anyhow::Result<()> {
+        return self.session.content_store_mut().flush();
+    }
+
+    /// Finish the revision and make it visible; without a commit it is discarded.
+    ///
+    /// The content is stored first, so a visible revision never references missing content.
+    pub fn commit(mut self) -> anyhow::Result<BackupSession> {
+        self.flush_content()?;
+        self.writer.finish()?.commit()
+            .with_context(|| "cannot write revision")?;
+
+        return Ok(self.session);
+    }
+
+    /// Add a file whose content is stored in `chunks`; they must be in the archive already.
+    pub fn add_chunked_file(
+        &mut self,
+        path: &Path,
+        checksum: &HashResult,
+        chunks: &[HashResult],
+        signature: &FileSignature,
+        meta: &FileMeta,
+    ) -> anyhow::Result<()> {
+        return self.add_file_entry(path, checksum, chunks, signature, meta);
+    }
+
+    fn add_file_entry(
+        &mut self,
+        path: &Path,
+        checksum: &HashResult,
+        chunks: &[HashResult],
+        signature: &FileSignature,
+        meta: &FileMeta,
+    ) -> anyhow::Result<()> {
         self.writer.add_entry(
             defs::keys::FILE,
             path.to_str()
@@ -46,28 +109,106 @@ impl<'a> ChannelWriter {
 
         self.writer.increase_depth();
         self.writer.add_entry(defs::keys::HASH, &checksum.to_string())?;
-        self.writer.decrease_depth();
-
-        //file
-        let target_path = defs::content_file(self.session.get_archive_dir(), &checksum.data());
-
-        if Path::is_file(&target_path) {
-            return Ok(ChannelWriterAdd::AlreadyExist);
+        for chunk in chunks {
+            self.writer.add_entry(defs::keys::CHUNK, &chunk.to_string())?;
         }
+        self.writer.add_entry(defs::keys::SIZE, &signature.size.to_string())?;
+        self.writer.add_entry(defs::keys::INODE, &signature.inode.to_string())?;
+        self.add_meta(meta)?;
+        self.writer.decrease_depth();
 
-        return Ok(ChannelWriterAdd::HashFile(target_path));
+        return Ok(());
     }
 
-    pub fn add_dir(&mut self, path: &Path) -> anyhow::Result<()> {
+    pub fn add_dir(&mut self, path: &Path, meta: &FileMeta) -> anyhow::Result<()> {
         self.writer.add_entry(
             defs::keys::DIR,
             path.to_str()
                 .ok_or(anyhow::Error::msg("Could not add entry"))?,
         )?;
 
+        self.writer.increase_depth();
+        self.add_meta(meta)?;
+        self.writer.decrease_depth();
+
         return Ok(())
     }
 
+    pub fn add_symlink(&mut self, path: &Path, target: &Path, meta: &FileMeta) -> anyhow::Result<()> {
+        self.add_item(defs::keys::SYMLINK, path)?;
+
+        self.writer.increase_depth();
+        self.writer.add_entry(
+            defs::keys::TARGET,
+            target.to_str()
+                .ok_or(anyhow::Error::msg("Could not add entry"))?,
+        )?;
+        self.add_meta(meta)?;
+        self.writer.decrease_depth();
+
+        return Ok(());
+    }
+
+    /// The first path written with the same inode, without remembering anything.
+    pub fn written_hardlink_target(&self, inode: (u64, u64)) -> Option<PathBuf> {
+        return self.hardlinks.get(&inode).cloned();
+    }
+
+    /// Returns the first path seen with the same inode, or remembers `path` for it.
+    ///
+    /// Call it in the same critical section as the following add so the
+    /// first path is always written before the hardlinks to it.
+    pub fn hardlink_target(&mut self, inode: (u64, u64), path: &Path) -> Option<PathBuf> {
+        if let Some(target) = self.hardlinks.get(&inode) {
+            return Some(target.clone());
+        }
+
+        self.hardlinks.insert(inode, path.to_owned());
+        return None;
+    }
+
+    pub fn add_hardlink(&mut self, path: &Path, target: &Path) -> anyhow::Result<()> {
+        self.add_item(defs::keys::HARDLINK, path)?;
+
+        self.writer.increase_depth();
+        self.writer.add_entry(
+            defs::keys::TARGET,
+            target.to_str()
+                .ok_or(anyhow::Error::msg("Could not add entry"))?,
+        )?;
+        self.writer.decrease_depth();
+
+        return Ok(());
+    }
+
+    pub fn add_special(&mut self, path: &Path, kind: SpecialKind, rdev: u64, meta: &FileMeta) -> anyhow::Result<()> {
+        self.add_item(defs::keys::SPECIAL, path)?;
+
+        self.writer.increase_depth();
+        self.writer.add_entry(defs::keys::TYPE, kind.as_str())?;
+        self.writer.add_entry(defs::keys::RDEV, &rdev.to_string())?;
+        self.add_meta(meta)?;
+        self.writer.decrease_depth();
+
+        return Ok(());
+    }
+
+    fn add_item(&mut self, key: &str, path: &Path) -> anyhow::Result<()> {
+        return self.writer.add_entry(
+            key,
+            path.to_str()
+                .ok_or(anyhow::Error::msg("Could not add entry"))?,
+        );
+    }
+
+    fn add_meta(&mut self, meta: &FileMeta) -> anyhow::Result<()> {
+        for (key, value) in meta.entries() {
+            self.writer.add_entry(key, &value)?;
+        }
+
+        return Ok(());
+    }
+
 }
 
 impl ToSession for ChannelWriter {
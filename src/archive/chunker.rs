@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context};
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+use crate::checksum::{HashResult, Hasher};
+use super::backend::Backend;
+use super::content::{self, ContentSettings};
+use super::content_store;
+use super::crypto::ContentKey;
+
+/// Whether large files are split into content-defined chunks.
+///
+/// Chunk boundaries depend on the data around them, so a change in a large
+/// file only produces new chunks around the change.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum ContentChunking {
+    #[default]
+    None,
+    FastCdc { min_size: u32, avg_size: u32, max_size: u32 },
+}
+
+impl ContentChunking {
+    /// Files smaller than the average chunk size are stored whole.
+    pub fn is_chunked(&self, file_size: u64) -> bool {
+        return match self {
+            ContentChunking::None => false,
+            ContentChunking::FastCdc { avg_size, .. } => file_size >= *avg_size as u64,
+        };
+    }
+}
+
+/// Parses `none` or `fastcdc[:avg_size]`; min and max size are a quarter and
+/// four times the average size.
+impl FromStr for ContentChunking {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<ContentChunking> {
+        let (name, avg_size) = match value.split_once(':') {
+            Some((name, avg_size)) => (name, Some(avg_size)),
+            None => (value, None),
+        };
+
+        return match name {
+            "none" if avg_size.is_none() => Ok(ContentChunking::None),
+            "fastcdc" => {
+                let avg_size: u32 = match avg_size {
+                    Some(avg_size) => avg_size.parse().with_context(|| format!("invalid chunk size {}", avg_size))?,
+                    None => 1024 * 1024,
+                };
+                if avg_size < fastcdc::v2020::AVERAGE_MIN * 4 || avg_size > fastcdc::v2020::AVERAGE_MAX {
+                    bail!("average chunk size must be in {}..={}", fastcdc::v2020::AVERAGE_MIN * 4, fastcdc::v2020::AVERAGE_MAX);
+                }
+                Ok(ContentChunking::FastCdc {
+                    min_size: avg_size / 4,
+                    avg_size: avg_size,
+                    max_size: avg_size * 4,
+                })
+            }
+            _ => Err(anyhow!("unknown chunking {}", value)),
+        };
+    }
+}
+
+pub struct ChunkedFile {
+    /// hash of the whole file
+    pub checksum: HashResult,
+    pub chunks: Vec<HashResult>,
+    /// count of chunks which were not in the archive yet
+    pub new_chunks: usize,
+}
+
+/// Split `path` into chunks and store each chunk which is not in the archive yet.
+///
+/// The file is read once; `hasher` gets the whole file, `chunk_hasher` each chunk.
+pub fn write_chunked_file(
+    path: &Path,
+    backend: &dyn Backend,
+    settings: &ContentSettings,
+    key: Option<&ContentKey>,
+    hasher: &mut dyn Hasher,
+    chunk_hasher: &mut dyn Hasher,
+) -> anyhow::Result<ChunkedFile> {
+    let ContentChunking::FastCdc { min_size, avg_size, max_size } = settings.chunking else {
+        bail!("archive does not store files in chunks");
+    };
+
+    let file = File::open(path)
+        .with_context(|| format!("cannot open source file {}", path.to_string_lossy()))?;
+
+    let mut chunks = Vec::new();
+    let mut new_chunks = 0;
+
+    for chunk in StreamCDC::new(file, min_size, avg_size, max_size) {
+        let chunk = chunk.with_context(|| format!("cannot read source file {}", path.to_string_lossy()))?;
+
+        hasher.update(&chunk.data);
+        chunk_hasher.update(&chunk.data);
+        let chunk_checksum = chunk_hasher.finalize();
+
+        let content_path = content_store::loose_path(settings.layout, &chunk_checksum);
+        if content::write_content_data(&chunk.data, backend, &content_path, settings, key)? {
+            new_chunks += 1;
+        }
+
+        chunks.push(chunk_checksum);
+    }
+
+    return Ok(ChunkedFile {
+        checksum: hasher.finalize(),
+        chunks: chunks,
+        new_chunks: new_chunks,
+    });
+}
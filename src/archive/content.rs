@@ -1,104 +1,434 @@
-use std::io::{BufWriter, BufReader};
+use std::fs::File;
+use std::io::{BufWriter, BufReader, Cursor};
 use std::io::{Write, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context};
 use bzip2::write::BzEncoder;
 use bzip2::read::BzDecoder;
 use bzip2::{self, Compression};
-use sha2::{Digest, Sha256};
 use serde::{Deserialize, Serialize};
-use serde_json::Result;
 
-use crate::checksum::HashAlgo;
+use crate::checksum::{self, HashAlgo, HashResult, Hasher};
+use crate::misc_helper;
+use super::backend::Backend;
+use super::chunker::ContentChunking;
+use super::content_store::{ContentLayout, ContentPacking};
+use super::crypto::{self, ContentEncryption, ContentKey, EncryptWriter};
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum ContentCompression {
     None,
     Bzip2{level: u32},
+    Zstd{level: i32},
+    Lz4,
+    Xz{level: u32},
+}
+/// How content files are named.
+///
+/// `Plain` uses the hash of the data, so anyone with access to the archive can
+/// check whether a known file is stored. `Keyed` uses a MAC keyed with the secret
+/// of the archive instead: HMAC for SHA-2, the keyed mode of BLAKE3. The secret
+/// is encrypted with the content key, so `Keyed` needs an encrypted archive.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum ContentAddressing {
+    #[default]
+    Plain,
+    Keyed,
 }
+
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct ContentSettings {
     pub compression: ContentCompression,
     pub hash_algo: HashAlgo,
+    #[serde(default)]
+    pub encryption: ContentEncryption,
+    #[serde(default)]
+    pub addressing: ContentAddressing,
+    #[serde(default)]
+    pub chunking: ContentChunking,
+    #[serde(default)]
+    pub packing: ContentPacking,
+    #[serde(default)]
+    pub layout: ContentLayout,
+}
+
+/// Every content file starts with this magic followed by one byte for the codec.
+///
+/// Content written before the header was introduced is plain bzip2, which
+/// starts with `BZh` and therefore never matches.
+const HEADER_MAGIC: &[u8; 3] = b"BKT";
+const HEADER_SIZE: usize = 4;
+
+impl ContentCompression {
+    fn codec_id(&self) -> u8 {
+        return match self {
+            ContentCompression::None => 0,
+            ContentCompression::Bzip2 { .. } => 1,
+            ContentCompression::Zstd { .. } => 2,
+            ContentCompression::Lz4 => 3,
+            ContentCompression::Xz { .. } => 4,
+        };
+    }
+
+    /// The level is only needed for writing; readers get the default one.
+    fn from_codec_id(id: u8) -> anyhow::Result<ContentCompression> {
+        return match id {
+            0 => Ok(ContentCompression::None),
+            1 => Ok(ContentCompression::Bzip2 { level: 9 }),
+            2 => Ok(ContentCompression::Zstd { level: 3 }),
+            3 => Ok(ContentCompression::Lz4),
+            4 => Ok(ContentCompression::Xz { level: 6 }),
+            _ => Err(anyhow!("unknown content codec {}", id)),
+        };
+    }
+}
+
+/// Parses `none`, `lz4`, `bzip2[:level]`, `zstd[:level]` and `xz[:level]`.
+impl FromStr for ContentCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<ContentCompression> {
+        let (name, level) = match value.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (value, None),
+        };
+
+        let level_in = |default: i64, min: i64, max: i64| -> anyhow::Result<i64> {
+            let Some(level) = level else {
+                return Ok(default);
+            };
+            let level: i64 = level.parse().with_context(|| format!("invalid level {}", level))?;
+            if level < min || level > max {
+                bail!("level of {} must be in {}..={}", name, min, max);
+            }
+            return Ok(level);
+        };
+
+        return match name {
+            "none" if level.is_none() => Ok(ContentCompression::None),
+            "lz4" if level.is_none() => Ok(ContentCompression::Lz4),
+            "bzip2" => Ok(ContentCompression::Bzip2 { level: level_in(9, 1, 9)? as u32 }),
+            "zstd" => Ok(ContentCompression::Zstd { level: level_in(3, 1, 22)? as i32 }),
+            "xz" => Ok(ContentCompression::Xz { level: level_in(6, 0, 9)? as u32 }),
+            _ => Err(anyhow!("unknown compression {}", value)),
+        };
+    }
+}
+
+/// Writer below the codec; encrypted content needs its last chunk written on finish.
+enum OuterWriter<'a> {
+    Plain(Box<dyn Write + 'a>),
+    Encrypted(EncryptWriter<Box<dyn Write + 'a>>),
+}
+
+impl OuterWriter<'_> {
+    fn finish(self) -> anyhow::Result<()> {
+        let mut writer = match self {
+            OuterWriter::Plain(writer) => writer,
+            OuterWriter::Encrypted(writer) => writer.finish()?,
+        };
+        writer.flush()?;
+        return Ok(());
+    }
+}
+
+impl Write for OuterWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        return match self {
+            OuterWriter::Plain(writer) => writer.write(buf),
+            OuterWriter::Encrypted(writer) => writer.write(buf),
+        };
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return match self {
+            OuterWriter::Plain(writer) => writer.flush(),
+            OuterWriter::Encrypted(writer) => writer.flush(),
+        };
+    }
 }
 
-fn create_compression<T: Write + 'static> (outer_writer: T, format: &ContentCompression) -> Box<dyn Write> {
-    return match format {
-        ContentCompression::None => 
-            Box::new(BufWriter::new(outer_writer)) as Box<dyn Write>,
-        ContentCompression::Bzip2 { level } => 
-            Box::new(BzEncoder::new(BufWriter::new(outer_writer), Compression::new(*level))) as Box<dyn Write>,
-    };
+enum Encoder<'a> {
+    None(BufWriter<OuterWriter<'a>>),
+    Bzip2(BzEncoder<BufWriter<OuterWriter<'a>>>),
+    Zstd(zstd::Encoder<'static, BufWriter<OuterWriter<'a>>>),
+    Lz4(lz4_flex::frame::FrameEncoder<BufWriter<OuterWriter<'a>>>),
+    Xz(xz2::write::XzEncoder<BufWriter<OuterWriter<'a>>>),
 }
 
-fn create_decompression<T: Read + 'static> (outer_reader: T, format: &ContentCompression) -> Box<dyn Read> {
-    return match format {
-        ContentCompression::None => 
+fn create_compression<'a>(outer_writer: OuterWriter<'a>, format: &ContentCompression) -> anyhow::Result<Encoder<'a>> {
+    let outer_writer = BufWriter::new(outer_writer);
+
+    return Ok(match format {
+        ContentCompression::None =>
+            Encoder::None(outer_writer),
+        ContentCompression::Bzip2 { level } =>
+            Encoder::Bzip2(BzEncoder::new(outer_writer, Compression::new(*level))),
+        ContentCompression::Zstd { level } =>
+            Encoder::Zstd(zstd::Encoder::new(outer_writer, *level)?),
+        ContentCompression::Lz4 =>
+            Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(outer_writer)),
+        ContentCompression::Xz { level } =>
+            Encoder::Xz(xz2::write::XzEncoder::new(outer_writer, *level)),
+    });
+}
+
+fn create_decompression(outer_reader: Box<dyn Read>, format: &ContentCompression) -> anyhow::Result<Box<dyn Read>> {
+    return Ok(match format {
+        ContentCompression::None =>
             Box::new(BufReader::new(outer_reader)) as Box<dyn Read>,
-        ContentCompression::Bzip2 { level } => 
-            Box::new(BzDecoder::new(BufReader::new(outer_reader))) as Box<dyn Read>,
-    };
-} 
+        ContentCompression::Bzip2 { .. } =>
+            Box::new(BzDecoder::new(outer_reader)) as Box<dyn Read>,
+        ContentCompression::Zstd { .. } =>
+            Box::new(zstd::Decoder::new(outer_reader)?) as Box<dyn Read>,
+        ContentCompression::Lz4 =>
+            Box::new(lz4_flex::frame::FrameDecoder::new(outer_reader)) as Box<dyn Read>,
+        ContentCompression::Xz { .. } =>
+            Box::new(xz2::read::XzDecoder::new(outer_reader)) as Box<dyn Read>,
+    });
+}
 
-pub struct ContentWriter
+/// Writes content into `outer_writer`, which may be borrowed, e.g. an object
+/// which is committed after [`ContentWriter::finish`].
+pub struct ContentWriter<'a>
 {
-    writer: Box<dyn Write>,
+    writer: Encoder<'a>,
     count: u64,
 }
 
-impl ContentWriter {
-    fn new(outer_writer: Box<dyn Write>, settings: &ContentSettings) -> ContentWriter {
-        return ContentWriter {
-            writer: create_compression(outer_writer, &settings.compression),
-            count: 0,
+impl<'a> ContentWriter<'a> {
+    pub fn new(outer_writer: Box<dyn Write + 'a>, settings: &ContentSettings, key: Option<&ContentKey>) -> anyhow::Result<ContentWriter<'a>> {
+        let mut outer_writer = match key {
+            Some(key) => OuterWriter::Encrypted(EncryptWriter::new(outer_writer, key)?),
+            None => OuterWriter::Plain(outer_writer),
         };
+        outer_writer.write_all(HEADER_MAGIC)?;
+        outer_writer.write_all(&[settings.compression.codec_id()])?;
+
+        return Ok(ContentWriter {
+            writer: create_compression(outer_writer, &settings.compression)?,
+            count: 0,
+        });
     }
 
-    fn bytes_written(&self) -> u64 {
+    pub fn bytes_written(&self) -> u64 {
         return self.count;
     }
+
+    /// Write the trailer of the codec; without it the content is truncated.
+    pub fn finish(self) -> anyhow::Result<u64> {
+        let outer_writer = match self.writer {
+            Encoder::None(writer) => writer,
+            Encoder::Bzip2(writer) => writer.finish()?,
+            Encoder::Zstd(writer) => writer.finish()?,
+            Encoder::Lz4(writer) => writer.finish()?,
+            Encoder::Xz(writer) => writer.finish()?,
+        };
+        outer_writer.into_inner()
+            .map_err(|err| err.into_error())?
+            .finish()?;
+
+        return Ok(self.count);
+    }
 }
 
-impl Write for ContentWriter {
+impl Write for ContentWriter<'_> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.count += buf.len() as u64;
-        return self.writer.write(buf);
+        let written = match &mut self.writer {
+            Encoder::None(writer) => writer.write(buf),
+            Encoder::Bzip2(writer) => writer.write(buf),
+            Encoder::Zstd(writer) => writer.write(buf),
+            Encoder::Lz4(writer) => writer.write(buf),
+            Encoder::Xz(writer) => writer.write(buf),
+        }?;
+        self.count += written as u64;
+        return Ok(written);
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        return self.writer.flush();
+        return match &mut self.writer {
+            Encoder::None(writer) => writer.flush(),
+            Encoder::Bzip2(writer) => writer.flush(),
+            Encoder::Zstd(writer) => writer.flush(),
+            Encoder::Lz4(writer) => writer.flush(),
+            Encoder::Xz(writer) => writer.flush(),
+        };
     }
 }
 
 pub struct ContentReader {
     reader: Box<dyn Read>,
-    digest: Option<Sha256>,
+    compression: ContentCompression,
+    digest: Option<Box<dyn Hasher>>,
 }
 
 impl ContentReader {
-    pub fn new(outer_reader: Box<dyn Read>, settings: &ContentSettings, with_hash: bool) -> ContentReader {
-        return ContentReader {
-            reader: create_decompression(outer_reader, &settings.compression),
-            digest: match with_hash {
-                true => Some(Sha256::new()),
-                false => None,
-            },
+    /// Decrypt and decompress `outer_reader`; the read data is fed into `digest` when given.
+    pub fn new(outer_reader: Box<dyn Read>, key: Option<&ContentKey>, digest: Option<Box<dyn Hasher>>) -> anyhow::Result<ContentReader> {
+        let mut outer_reader = crypto::decrypt_reader(outer_reader, key)?;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        (&mut outer_reader).take(HEADER_SIZE as u64).read_to_end(&mut header)?;
+
+        let (compression, outer_reader) = match header.starts_with(HEADER_MAGIC) && header.len() == HEADER_SIZE {
+            true => (ContentCompression::from_codec_id(header[HEADER_SIZE - 1])?, outer_reader),
+            false => (
+                ContentCompression::Bzip2 { level: 9 },
+                Box::new(Cursor::new(header).chain(outer_reader)) as Box<dyn Read>,
+            ),
         };
+
+        return Ok(ContentReader {
+            reader: create_decompression(outer_reader, &compression)?,
+            compression: compression,
+            digest: digest,
+        });
+    }
+
+    pub fn compression(&self) -> &ContentCompression {
+        return &self.compression;
     }
 
-    //TODO: get hash value
+    /// The hash of the data read so far; None without a digest.
+    pub fn hash(&mut self) -> Option<HashResult> {
+        return self.digest.as_mut().map(|x| x.finalize());
+    }
 }
 
 impl Read for ContentReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let result = self.reader.read(buf);
+        let read_size = self.reader.read(buf)?;
 
-        if result.is_err() {
-            return result;
-        }
-        
         if let Some(digest) = &mut self.digest {
-            digest.update(&buf);
+            digest.update(&buf[0..read_size]);
         }
-        
-        return result;
+
+        return Ok(read_size);
     }
 }
+
+/// Passes the data of `reader` through and feeds it into `hasher`.
+struct HashingReader<'a, R: Read> {
+    reader: R,
+    hasher: &'a mut dyn Hasher,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read_size = self.reader.read(buf)?;
+        self.hasher.update(&buf[0..read_size]);
+        return Ok(read_size);
+    }
+}
+
+/// Open the source file `src`; the read data is fed into `hasher`.
+fn open_source<'a>(src: &Path, hasher: &'a mut dyn Hasher) -> anyhow::Result<HashingReader<'a, File>> {
+    let src_file = File::open(src)
+        .with_context(|| format!("cannot open source file {}", src.to_string_lossy()))?;
+    return Ok(HashingReader {
+        reader: src_file,
+        hasher: hasher,
+    });
+}
+
+/// Fails when the data read from `src` has not the hash it was stored under,
+/// i.e. it was changed after it was hashed.
+fn check_source(src: &Path, source: HashingReader<'_, File>, checksum: &HashResult) -> anyhow::Result<()> {
+    let calculated = source.hasher.finalize();
+    if calculated.data() != checksum.data() {
+        bail!("{} was changed while it was backed up; back it up again", src.to_string_lossy());
+    }
+
+    return Ok(());
+}
+
+/// Compress `src` into the content file `dst`; returns the uncompressed size.
+///
+/// The data is hashed again with `hasher` while it is written; the file is only
+/// committed when it still has the hash `checksum`.
+pub fn write_content_file(
+    src: &Path,
+    checksum: &HashResult,
+    hasher: &mut dyn Hasher,
+    backend: &dyn Backend,
+    dst: &str,
+    settings: &ContentSettings,
+    key: Option<&ContentKey>,
+) -> anyhow::Result<u64> {
+    let mut source = open_source(src, hasher)?;
+    let mut dst_writer = backend.put(dst)
+        .with_context(|| format!("cannot open destination file {}", dst))?;
+
+    let mut writer = ContentWriter::new(Box::new(&mut dst_writer), settings, key)?;
+    misc_helper::copy_stream(&mut source, &mut writer)
+        .with_context(|| format!("cannot write content file {}", dst))?;
+    let size = writer.finish()?;
+    check_source(src, source, checksum)?;
+
+    //a half written file must never appear under the hash of the complete one
+    dst_writer.commit()
+        .with_context(|| format!("cannot write content file {}", dst))?;
+
+    return Ok(size);
+}
+
+/// Compress `data` into the content file `dst` unless it exists already.
+///
+/// Returns false when the content was already stored.
+pub fn write_content_data(data: &[u8], backend: &dyn Backend, dst: &str, settings: &ContentSettings, key: Option<&ContentKey>) -> anyhow::Result<bool> {
+    if backend.exists(dst)? {
+        return Ok(false);
+    }
+
+    let mut dst_writer = backend.put(dst)
+        .with_context(|| format!("cannot open destination file {}", dst))?;
+
+    let mut writer = ContentWriter::new(Box::new(&mut dst_writer), settings, key)?;
+    writer.write_all(data)
+        .with_context(|| format!("cannot write content file {}", dst))?;
+    writer.finish()?;
+
+    dst_writer.commit()
+        .with_context(|| format!("cannot write content file {}", dst))?;
+
+    return Ok(true);
+}
+
+/// Write target whose data is still available after the writer owning it is dropped.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().expect("shared buffer poisoned").extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// Compress the file `src` into memory, e.g. to append it to a pack.
+///
+/// Like [`write_content_file`] it fails when `src` has no longer the hash `checksum`.
+pub fn encode_content_file(
+    src: &Path,
+    checksum: &HashResult,
+    hasher: &mut dyn Hasher,
+    settings: &ContentSettings,
+    key: Option<&ContentKey>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut source = open_source(src, hasher)?;
+    let buffer = SharedBuffer::default();
+
+    let mut writer = ContentWriter::new(Box::new(buffer.clone()), settings, key)?;
+    misc_helper::copy_stream(&mut source, &mut writer)
+        .with_context(|| format!("cannot read source file {}", src.to_string_lossy()))?;
+    writer.finish()?;
+    check_source(src, source, checksum)?;
+
+    return Ok(std::mem::take(&mut *buffer.0.lock().expect("shared buffer poisoned")));
+}
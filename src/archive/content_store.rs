@@ -0,0 +1,518 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use anyhow::{anyhow, bail, Context};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use crate::checksum::{HashResult, Hasher};
+use super::backend::{Backend, ObjectWriter};
+use super::content::{ContentReader, ContentSettings};
+use super::crypto::ContentKey;
+use super::defs;
+
+/// Whether small content is appended to pack files instead of getting a file of its own.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum ContentPacking {
+    #[default]
+    None,
+    Packs { max_blob_size: u64, max_pack_size: u64 },
+}
+
+/// Parses `none` or `packs[:max_blob_size]`; packs are closed at 64 MiB.
+impl FromStr for ContentPacking {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<ContentPacking> {
+        let (name, max_blob_size) = match value.split_once(':') {
+            Some((name, max_blob_size)) => (name, Some(max_blob_size)),
+            None => (value, None),
+        };
+
+        return match name {
+            "none" if max_blob_size.is_none() => Ok(ContentPacking::None),
+            "packs" => Ok(ContentPacking::Packs {
+                max_blob_size: match max_blob_size {
+                    Some(size) => size.parse().with_context(|| format!("invalid blob size {}", size))?,
+                    None => 64 * 1024,
+                },
+                max_pack_size: 64 * 1024 * 1024,
+            }),
+            _ => Err(anyhow!("unknown packing {}", value)),
+        };
+    }
+}
+
+/// How loose content files are placed in the content dir.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum ContentLayout {
+    /// `content/<hash>`
+    #[default]
+    Flat,
+    /// `content/ab/cd/<hash>`, so no dir gets too many entries
+    Sharded,
+}
+
+impl ContentLayout {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            ContentLayout::Flat => "flat",
+            ContentLayout::Sharded => "sharded",
+        };
+    }
+}
+
+impl FromStr for ContentLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<ContentLayout> {
+        return match value {
+            "flat" => Ok(ContentLayout::Flat),
+            "sharded" => Ok(ContentLayout::Sharded),
+            _ => Err(anyhow!("unknown layout {}", value)),
+        };
+    }
+}
+
+/// Where a packed content blob is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackLocation {
+    pub pack: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Clone, Debug)]
+pub enum ContentLocation {
+    /// key of the content file
+    Loose(String),
+    Packed(PackLocation),
+}
+
+/// A stored content blob; `id` is the hex digest it is named after.
+#[derive(Clone, Debug)]
+pub struct StoredContent {
+    pub id: String,
+    pub location: ContentLocation,
+}
+
+struct PackWriter {
+    name: String,
+    writer: BufWriter<Box<dyn ObjectWriter>>,
+    entries: Vec<(String, u64, u64)>,
+    size: u64,
+}
+
+/// All content of an archive, either as loose files in `content/` or packed.
+///
+/// Packed content is found with the global index `packs/index`; each pack
+/// `packs/<name>.pack` has its own index `packs/<name>.idx`, so the global one
+/// can be rebuilt. A pack only becomes visible once its index is written.
+pub struct ContentStore {
+    backend: Arc<dyn Backend>,
+    settings: ContentSettings,
+    key: Option<ContentKey>,
+    index: HashMap<String, PackLocation>,
+    pack_writer: Option<PackWriter>,
+}
+
+pub fn loose_path(layout: ContentLayout, checksum: &HashResult) -> String {
+    return layout_path(layout, checksum.data());
+}
+
+fn layout_path(layout: ContentLayout, hash: &[u8]) -> String {
+    return match layout {
+        ContentLayout::Flat => defs::content_file(hash),
+        ContentLayout::Sharded => defs::content_file_sharded(hash),
+    };
+}
+
+fn parse_index_line(line: &str, pack: Option<&str>) -> anyhow::Result<(String, PackLocation)> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    let (id, pack, offset, length) = match (pack, parts.as_slice()) {
+        (Some(pack), [id, offset, length]) => (*id, pack, *offset, *length),
+        (None, [id, pack, offset, length]) => (*id, *pack, *offset, *length),
+        _ => bail!("invalid index line {}", line),
+    };
+
+    return Ok((id.to_owned(), PackLocation {
+        pack: pack.to_owned(),
+        offset: offset.parse().with_context(|| format!("invalid index line {}", line))?,
+        length: length.parse().with_context(|| format!("invalid index line {}", line))?,
+    }));
+}
+
+fn read_index_file(backend: &dyn Backend, key: &str, pack: Option<&str>) -> anyhow::Result<Vec<(String, PackLocation)>> {
+    let mut ret = Vec::new();
+    let reader = backend.get(key)
+        .with_context(|| format!("cannot open index {}", key))?;
+
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        ret.push(parse_index_line(&line, pack)?);
+    }
+
+    return Ok(ret);
+}
+
+impl ContentStore {
+    /// `repair` writes the global index when it misses packs; only with the exclusive lock.
+    pub fn open(backend: Arc<dyn Backend>, settings: &ContentSettings, key: Option<&ContentKey>, repair: bool) -> anyhow::Result<ContentStore> {
+        let mut store = ContentStore {
+            backend: backend,
+            settings: *settings,
+            key: key.cloned(),
+            index: HashMap::new(),
+            pack_writer: None,
+        };
+        store.load_index(repair)?;
+
+        return Ok(store);
+    }
+
+    /// Read the global index and add packs it misses, e.g. after an interrupted run.
+    fn load_index(&mut self, repair: bool) -> anyhow::Result<()> {
+        let pack_names = self.pack_names()?;
+        let index_file = defs::pack_index_file();
+
+        if self.backend.exists(&index_file)? {
+            for (id, location) in read_index_file(self.backend.as_ref(), &index_file, None)? {
+                self.index.insert(id, location);
+            }
+        }
+
+        let mut known_packs: HashSet<String> = self.index.values().map(|x| x.pack.clone()).collect();
+        let mut changed = false;
+
+        //packs removed by an interrupted gc
+        for pack in known_packs.clone() {
+            if !pack_names.contains(&pack) {
+                self.index.retain(|_, location| location.pack != pack);
+                known_packs.remove(&pack);
+                changed = true;
+            }
+        }
+
+        for pack in pack_names {
+            if known_packs.contains(&pack) {
+                continue;
+            }
+            for (id, location) in read_index_file(self.backend.as_ref(), &defs::pack_idx_file(&pack), Some(&pack))? {
+                self.index.insert(id, location);
+            }
+            changed = true;
+        }
+
+        if changed && repair {
+            self.write_index()?;
+        }
+
+        return Ok(());
+    }
+
+    fn write_index(&self) -> anyhow::Result<()> {
+        let mut content = String::new();
+        for (id, location) in &self.index {
+            content += &format!("{} {} {} {}\n", id, location.pack, location.offset, location.length);
+        }
+
+        return self.backend.put_data(&defs::pack_index_file(), content.as_bytes());
+    }
+
+    /// Names of all packs with an index; packs without one were never finished.
+    fn pack_names(&self) -> anyhow::Result<Vec<String>> {
+        let mut ret = Vec::new();
+        let idx_suffix = format!(".{}", defs::PACK_IDX_EXTENSION);
+
+        for key in self.backend.list(defs::PACKS_DIR)? {
+            if let Some(name) = defs::key_name(&key).strip_suffix(&idx_suffix) {
+                ret.push(name.to_owned());
+            }
+        }
+
+        return Ok(ret);
+    }
+
+    pub fn contains(&self, checksum: &HashResult) -> anyhow::Result<bool> {
+        return Ok(self.index.contains_key(&checksum.to_hex())
+            || self.existing_loose_path(checksum)?.is_some());
+    }
+
+    /// Key for new loose content in the layout of the settings.
+    pub fn loose_path(&self, checksum: &HashResult) -> String {
+        return loose_path(self.settings.layout, checksum);
+    }
+
+    /// Loose content is looked up in both layouts, so an interrupted migration loses nothing.
+    fn existing_loose_path(&self, checksum: &HashResult) -> anyhow::Result<Option<String>> {
+        let other = match self.settings.layout {
+            ContentLayout::Flat => ContentLayout::Sharded,
+            ContentLayout::Sharded => ContentLayout::Flat,
+        };
+
+        for key in [self.settings.layout, other].map(|layout| loose_path(layout, checksum)) {
+            if self.backend.exists(&key)? {
+                return Ok(Some(key));
+            }
+        }
+
+        return Ok(None);
+    }
+
+    /// Whether content of a file with `size` bytes goes into a pack.
+    pub fn wants_pack(&self, size: u64) -> bool {
+        return match self.settings.packing {
+            ContentPacking::None => false,
+            ContentPacking::Packs { max_blob_size, .. } => size <= max_blob_size,
+        };
+    }
+
+    fn location(&self, checksum: &HashResult) -> anyhow::Result<ContentLocation> {
+        if let Some(location) = self.index.get(&checksum.to_hex()) {
+            return Ok(ContentLocation::Packed(location.clone()));
+        }
+
+        if let Some(key) = self.existing_loose_path(checksum)? {
+            return Ok(ContentLocation::Loose(key));
+        }
+
+        bail!("content {} is missing", checksum.to_hex());
+    }
+
+    /// The stored bytes of a blob, still compressed and encrypted.
+    fn open_raw(&self, location: &ContentLocation) -> anyhow::Result<Box<dyn Read + Send>> {
+        return match location {
+            ContentLocation::Loose(key) => self.backend.get(key)
+                .with_context(|| format!("cannot open content file {}", key)),
+            ContentLocation::Packed(location) => self.backend
+                .get_range(&defs::pack_file(&location.pack), location.offset, location.length)
+                .with_context(|| format!("cannot open pack {}", location.pack)),
+        };
+    }
+
+    pub fn open_stored(&self, content: &StoredContent, digest: Option<Box<dyn Hasher>>) -> anyhow::Result<ContentReader> {
+        return ContentReader::new(self.open_raw(&content.location)?, self.key.as_ref(), digest);
+    }
+
+    pub fn open_content(&self, checksum: &HashResult, digest: Option<Box<dyn Hasher>>) -> anyhow::Result<ContentReader> {
+        return ContentReader::new(self.open_raw(&self.location(checksum)?)?, self.key.as_ref(), digest);
+    }
+
+    /// Append an already encoded blob to the current pack; returns false when it is stored already.
+    pub fn add_packed(&mut self, checksum: &HashResult, blob: &[u8]) -> anyhow::Result<bool> {
+        let id = checksum.to_hex();
+        if self.contains(checksum)? || self.pack_writer.as_ref().is_some_and(|x| x.entries.iter().any(|e| e.0 == id)) {
+            return Ok(false);
+        }
+
+        let ContentPacking::Packs { max_pack_size, .. } = self.settings.packing else {
+            bail!("archive does not use packs");
+        };
+
+        self.append_packed(id, blob, max_pack_size)?;
+        return Ok(true);
+    }
+
+    /// Store an already encoded blob of `size` uncompressed bytes, loose or
+    /// packed like new content; returns false when it is stored already.
+    pub fn add_encoded(&mut self, checksum: &HashResult, size: u64, blob: &mut dyn Read) -> anyhow::Result<bool> {
+        if self.contains(checksum)? {
+            return Ok(false);
+        }
+
+        if self.wants_pack(size) {
+            let mut data = Vec::new();
+            blob.read_to_end(&mut data)?;
+            return self.add_packed(checksum, &data);
+        }
+
+        let key = self.loose_path(checksum);
+        let mut writer = self.backend.put(&key)
+            .with_context(|| format!("cannot create content file {}", key))?;
+        std::io::copy(blob, &mut writer)
+            .with_context(|| format!("cannot write content file {}", key))?;
+        writer.commit()?;
+
+        return Ok(true);
+    }
+
+    fn append_packed(&mut self, id: String, blob: &[u8], max_pack_size: u64) -> anyhow::Result<()> {
+        if self.pack_writer.is_none() {
+            let name = format!("{:016x}", StdRng::from_os_rng().next_u64());
+            let writer = self.backend.put(&defs::pack_file(&name))
+                .with_context(|| format!("cannot create pack {}", name))?;
+            self.pack_writer = Some(PackWriter {
+                name: name,
+                writer: BufWriter::new(writer),
+                entries: Vec::new(),
+                size: 0,
+            });
+        }
+
+        let pack_writer = self.pack_writer.as_mut().expect("pack writer is open");
+        pack_writer.writer.write_all(blob)?;
+        pack_writer.entries.push((id, pack_writer.size, blob.len() as u64));
+        pack_writer.size += blob.len() as u64;
+
+        if pack_writer.size >= max_pack_size {
+            self.flush()?;
+        }
+
+        return Ok(());
+    }
+
+    /// Finish the current pack: its data, then its index, then the global index.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        let Some(pack_writer) = self.pack_writer.take() else {
+            return Ok(());
+        };
+
+        let writer = pack_writer.writer.into_inner()
+            .map_err(|err| anyhow!("cannot write pack {}: {}", pack_writer.name, err.error()))?;
+        writer.commit()
+            .with_context(|| format!("cannot write pack {}", pack_writer.name))?;
+
+        let mut content = String::new();
+        for (id, offset, length) in &pack_writer.entries {
+            content += &format!("{} {} {}\n", id, offset, length);
+        }
+        self.backend.put_data(&defs::pack_idx_file(&pack_writer.name), content.as_bytes())?;
+
+        for (id, offset, length) in pack_writer.entries {
+            self.index.insert(id, PackLocation {
+                pack: pack_writer.name.clone(),
+                offset: offset,
+                length: length,
+            });
+        }
+
+        return self.write_index();
+    }
+
+    /// Every stored blob; a blob may be listed twice when it is stored loose and packed.
+    pub fn list(&self) -> anyhow::Result<Vec<StoredContent>> {
+        let mut ret = Vec::new();
+
+        for key in defs::content_paths(self.backend.as_ref())? {
+            ret.push(StoredContent {
+                id: defs::key_name(&key).to_owned(),
+                location: ContentLocation::Loose(key),
+            });
+        }
+
+        for (id, location) in &self.index {
+            ret.push(StoredContent {
+                id: id.clone(),
+                location: ContentLocation::Packed(location.clone()),
+            });
+        }
+
+        return Ok(ret);
+    }
+
+    /// Size of a stored blob in the archive.
+    pub fn stored_size(&self, content: &StoredContent) -> anyhow::Result<u64> {
+        return Ok(match &content.location {
+            ContentLocation::Loose(key) => self.backend.stat(key)?.size,
+            ContentLocation::Packed(location) => location.length,
+        });
+    }
+
+    /// Move every loose content file into `layout`; returns the number of moved files.
+    ///
+    /// Files whose name is not a hash are left alone; gc removes them.
+    pub fn migrate_layout(&mut self, layout: ContentLayout) -> anyhow::Result<usize> {
+        self.settings.layout = layout;
+        let mut moved = 0;
+
+        for key in defs::content_paths(self.backend.as_ref())? {
+            let Ok(hash) = hex::decode(defs::key_name(&key)) else {
+                continue;
+            };
+            if hash.len() < 2 {
+                continue;
+            }
+
+            let target = layout_path(layout, &hash);
+            if target == key {
+                continue;
+            }
+
+            //a blob stored in both layouts has the same content
+            if self.backend.exists(&target)? {
+                self.backend.delete(&key)?;
+            } else {
+                self.backend.rename(&key, &target)?;
+            }
+            moved += 1;
+        }
+
+        return Ok(moved);
+    }
+
+    /// Remove every blob which is not in `keep`; returns the removed blobs.
+    ///
+    /// Packs with removed blobs are rewritten with the remaining ones. The new
+    /// pack is finished and indexed before the old one is deleted.
+    pub fn retain(&mut self, keep: &HashSet<String>, dry_run: bool) -> anyhow::Result<Vec<StoredContent>> {
+        self.flush()?;
+
+        let removed: Vec<StoredContent> = self.list()?
+            .into_iter()
+            .filter(|x| !keep.contains(&x.id))
+            .collect();
+
+        if dry_run {
+            return Ok(removed);
+        }
+
+        let max_pack_size = match self.settings.packing {
+            ContentPacking::Packs { max_pack_size, .. } => max_pack_size,
+            ContentPacking::None => 64 * 1024 * 1024,
+        };
+
+        let mut dirty_packs = HashSet::new();
+        for content in &removed {
+            match &content.location {
+                ContentLocation::Loose(key) => self.backend.delete(key)?,
+                ContentLocation::Packed(location) => {
+                    dirty_packs.insert(location.pack.clone());
+                }
+            }
+        }
+
+        for pack in dirty_packs {
+            let remaining: Vec<(String, PackLocation)> = self.index
+                .iter()
+                .filter(|(id, location)| location.pack == pack && keep.contains(*id))
+                .map(|(id, location)| (id.clone(), location.clone()))
+                .collect();
+
+            for (id, location) in remaining {
+                let mut blob = Vec::new();
+                self.open_raw(&ContentLocation::Packed(location))?.read_to_end(&mut blob)?;
+                self.append_packed(id, &blob, max_pack_size)?;
+            }
+            self.flush()?;
+
+            self.index.retain(|_, location| location.pack != pack);
+            self.write_index()?;
+            self.backend.delete(&defs::pack_idx_file(&pack))?;
+            self.backend.delete(&defs::pack_file(&pack))?;
+        }
+
+        return Ok(removed);
+    }
+}
+
+impl Drop for ContentStore {
+    fn drop(&mut self) {
+        //only an explicit flush commits a pack; dropping the writer discards its temp object
+        if let Some(pack_writer) = self.pack_writer.take() {
+            eprintln!("discard unfinished pack {} with {} blobs", pack_writer.name, pack_writer.entries.len());
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use std::io::{self, Read, Write};
+use anyhow::{anyhow, bail, Context};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use super::backend::ObjectWriter;
+
+/// Every encrypted file starts with this magic followed by a version byte.
+const HEADER_MAGIC: &[u8; 3] = b"BKE";
+const HEADER_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 4;
+
+/// XChaCha20 nonce size minus the counter and last-block flag of the STREAM construction.
+const NONCE_PREFIX_SIZE: usize = 19;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum ContentEncryption {
+    #[default]
+    None,
+    XChaCha20Poly1305,
+}
+
+/// Random archive key which encrypts content and revisions.
+///
+/// It never touches the disk in plain form; see [`WrappedKey`].
+#[derive(Clone)]
+pub struct ContentKey {
+    key: Key,
+}
+
+/// The archive key encrypted with a key derived from the password.
+#[derive(Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub kdf: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub key: String,
+}
+
+pub fn random_bytes(count: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; count];
+    StdRng::from_os_rng().fill_bytes(&mut bytes);
+    return bytes;
+}
+
+fn derive_key(password: &str, salt: &[u8], m_cost: u32, t_cost: u32, p_cost: u32) -> anyhow::Result<Key> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(KEY_SIZE))
+        .map_err(|err| anyhow!("invalid argon2 parameters: {}", err))?;
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("cannot derive key from password: {}", err))?;
+    return Ok(key);
+}
+
+impl ContentKey {
+    pub fn generate() -> ContentKey {
+        return ContentKey {
+            key: *Key::from_slice(&random_bytes(KEY_SIZE)),
+        };
+    }
+
+    pub fn data(&self) -> &[u8] {
+        return &self.key;
+    }
+
+    pub fn wrap(&self, password: &str) -> anyhow::Result<WrappedKey> {
+        let params = Params::default();
+        let salt = random_bytes(SALT_SIZE);
+        let nonce = random_bytes(XNonce::default().len());
+
+        let kek = derive_key(password, &salt, params.m_cost(), params.t_cost(), params.p_cost())?;
+        let wrapped = XChaCha20Poly1305::new(&kek)
+            .encrypt(XNonce::from_slice(&nonce), self.key.as_slice())
+            .map_err(|_| anyhow!("cannot wrap archive key"))?;
+
+        return Ok(WrappedKey {
+            kdf: "argon2id".into(),
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            key: hex::encode(wrapped),
+        });
+    }
+
+    pub fn unwrap(wrapped: &WrappedKey, password: &str) -> anyhow::Result<ContentKey> {
+        if wrapped.kdf != "argon2id" {
+            bail!("unknown key derivation {}", wrapped.kdf);
+        }
+
+        let salt = hex::decode(&wrapped.salt)?;
+        let nonce = hex::decode(&wrapped.nonce)?;
+        if nonce.len() != XNonce::default().len() {
+            bail!("invalid nonce size of wrapped key");
+        }
+
+        let kek = derive_key(password, &salt, wrapped.m_cost, wrapped.t_cost, wrapped.p_cost)?;
+        let key = XChaCha20Poly1305::new(&kek)
+            .decrypt(XNonce::from_slice(&nonce), hex::decode(&wrapped.key)?.as_slice())
+            .map_err(|_| anyhow!("wrong password"))?;
+
+        if key.len() != KEY_SIZE {
+            bail!("invalid archive key size");
+        }
+
+        return Ok(ContentKey {
+            key: *Key::from_slice(&key),
+        });
+    }
+}
+
+/// Encrypts a stream in chunks with the STREAM construction, so that reordered,
+/// modified or truncated chunks are detected on decryption.
+///
+/// The last chunk is only written by [`EncryptWriter::finish`]. A dropped writer
+/// leaves its output without it, so an aborted write never decrypts as complete.
+pub struct EncryptWriter<W: Write> {
+    writer: Option<W>,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(writer: W, key: &ContentKey) -> anyhow::Result<EncryptWriter<W>> {
+        let mut writer = writer;
+        let nonce_prefix = random_bytes(NONCE_PREFIX_SIZE);
+
+        writer.write_all(HEADER_MAGIC)?;
+        writer.write_all(&[HEADER_VERSION])?;
+        writer.write_all(&nonce_prefix)?;
+
+        return Ok(EncryptWriter {
+            writer: Some(writer),
+            encryptor: Some(EncryptorBE32::from_aead(
+                XChaCha20Poly1305::new(&key.key),
+                nonce_prefix.as_slice().into(),
+            )),
+            buffer: Vec::with_capacity(CHUNK_SIZE + 1),
+        });
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.finish_last()?;
+        return Ok(self.writer.take().expect("encrypt writer already finished"));
+    }
+
+    fn finish_last(&mut self) -> anyhow::Result<()> {
+        let Some(encryptor) = self.encryptor.take() else {
+            return Ok(());
+        };
+        let writer = self.writer.as_mut().expect("encrypt writer already finished");
+
+        let chunk = encryptor
+            .encrypt_last(self.buffer.as_slice())
+            .map_err(|_| anyhow!("cannot encrypt"))?;
+        writer.write_all(&chunk)?;
+        writer.flush()?;
+        self.buffer.clear();
+
+        return Ok(());
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (Some(encryptor), Some(writer)) = (&mut self.encryptor, &mut self.writer) else {
+            return Err(io::Error::other("encrypt writer already finished"));
+        };
+
+        //only a chunk which is followed by more data may be written as non-last chunk
+        let mut written = 0;
+        while written < buf.len() {
+            if self.buffer.len() == CHUNK_SIZE {
+                let chunk = encryptor
+                    .encrypt_next(self.buffer.as_slice())
+                    .map_err(|_| io::Error::other("cannot encrypt"))?;
+                writer.write_all(&chunk)?;
+                self.buffer.clear();
+            }
+
+            let take = (CHUNK_SIZE - self.buffer.len()).min(buf.len() - written);
+            self.buffer.extend_from_slice(&buf[written..written + take]);
+            written += take;
+        }
+
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        };
+    }
+}
+
+/// An encrypted object; its last chunk is written on commit.
+impl ObjectWriter for EncryptWriter<Box<dyn ObjectWriter>> {
+    fn commit(self: Box<Self>) -> anyhow::Result<()> {
+        return (*self).finish()?.commit();
+    }
+}
+
+pub struct DecryptReader<R: Read> {
+    reader: R,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    lookahead: Option<u8>,
+    plain: Vec<u8>,
+    plain_pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Expects that the header magic was already consumed by the caller.
+    fn from_body(reader: R, key: &ContentKey) -> anyhow::Result<DecryptReader<R>> {
+        let mut reader = reader;
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        reader.read_exact(&mut nonce_prefix)
+            .with_context(|| "encrypted file is truncated")?;
+
+        return Ok(DecryptReader {
+            reader: reader,
+            decryptor: Some(DecryptorBE32::from_aead(
+                XChaCha20Poly1305::new(&key.key),
+                nonce_prefix.as_slice().into(),
+            )),
+            lookahead: None,
+            plain: Vec::new(),
+            plain_pos: 0,
+        });
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let Some(decryptor) = &mut self.decryptor else {
+            return Ok(());
+        };
+
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+        if let Some(byte) = self.lookahead.take() {
+            chunk.push(byte);
+        }
+        (&mut self.reader)
+            .take((CHUNK_SIZE + TAG_SIZE - chunk.len()) as u64)
+            .read_to_end(&mut chunk)?;
+
+        let mut next = [0u8; 1];
+        let is_last = loop {
+            match self.reader.read(&mut next) {
+                Ok(0) => break true,
+                Ok(_) => break false,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        };
+
+        self.plain = match is_last {
+            true => self.decryptor.take().expect("decryptor is available")
+                .decrypt_last(chunk.as_slice()),
+            false => {
+                self.lookahead = Some(next[0]);
+                decryptor.decrypt_next(chunk.as_slice())
+            }
+        }
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decryption failed; data is corrupt or truncated"))?;
+        self.plain_pos = 0;
+
+        return Ok(());
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.plain_pos == self.plain.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+
+        let count = buf.len().min(self.plain.len() - self.plain_pos);
+        buf[..count].copy_from_slice(&self.plain[self.plain_pos..self.plain_pos + count]);
+        self.plain_pos += count;
+
+        return Ok(count);
+    }
+}
+
+/// Returns a reader with the plain data of `reader`.
+///
+/// Without a key the data is passed through unchanged; encrypted data needs the key.
+pub fn decrypt_reader(reader: Box<dyn Read>, key: Option<&ContentKey>) -> anyhow::Result<Box<dyn Read>> {
+    let mut reader = reader;
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    (&mut reader).take(HEADER_SIZE as u64).read_to_end(&mut header)?;
+
+    if header.len() == HEADER_SIZE && header.starts_with(HEADER_MAGIC) {
+        if header[HEADER_SIZE - 1] != HEADER_VERSION {
+            bail!("unknown encryption version {}", header[HEADER_SIZE - 1]);
+        }
+        let key = key.ok_or(anyhow!("data is encrypted; a password is needed"))?;
+        return Ok(Box::new(DecryptReader::from_body(reader, key)?));
+    }
+
+    //in an encrypted archive plain data can only come from tampering
+    if key.is_some() {
+        bail!("data of an encrypted archive is not encrypted");
+    }
+
+    return Ok(Box::new(io::Cursor::new(header).chain(reader)));
+}
@@ -1,109 +1,193 @@
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::time::SystemTime;
 use anyhow::{anyhow, bail, Context};
-use clap::builder::PathBufValueParser;
 use rand::{rngs::StdRng, RngCore, SeedableRng};
-use crate::dirwalk::{DirWalk, DirWalkParameters};
-use crate::misc_helper;
-use chrono::{Datelike, Timelike, Utc};
+use super::backend::Backend;
+use chrono::{DateTime, Datelike, NaiveDateTime, Timelike, Utc};
 
 pub const CONTENT_DIR: &str = "content";
+pub const PACKS_DIR: &str = "packs";
+pub const PACK_EXTENSION: &str = "pack";
+pub const PACK_IDX_EXTENSION: &str = "idx";
+pub const PACK_INDEX_FILE: &str = "index";
 pub const CHANNEL_DIR: &str = "channels";
 pub const LOCK_FILE: &str = "lock";
+pub const LOCKS_DIR: &str = "locks";
 pub const SETTINGS_FILE: &str = "settings.json";
+pub const KEY_FILE: &str = "key.json";
+pub const SECRET_FILE: &str = "secret";
 pub mod keys {
     pub const FILE: &str = "file";
     pub const DIR: &str = "dir";
+    pub const SYMLINK: &str = "symlink";
+    pub const HARDLINK: &str = "hardlink";
+    pub const SPECIAL: &str = "special";
+    pub const TARGET: &str = "target";
+    pub const TYPE: &str = "type";
+    pub const RDEV: &str = "rdev";
     pub const HASH: &str = "hash";
+    pub const MODE: &str = "mode";
+    pub const UID: &str = "uid";
+    pub const GID: &str = "gid";
+    pub const MTIME: &str = "mtime";
+    pub const ATIME: &str = "atime";
+    pub const XATTR: &str = "xattr";
+    pub const SIZE: &str = "size";
+    pub const INODE: &str = "inode";
+    pub const CHUNK: &str = "chunk";
+
+    /// Keys which start a new item in a revision.
+    pub fn is_item(key: &str) -> bool {
+        return [FILE, DIR, SYMLINK, HARDLINK, SPECIAL].contains(&key);
+    }
 }
 
-pub fn settings_file(archive_dir:&Path) ->PathBuf {
-    return archive_dir.to_path_buf().join(SETTINGS_FILE);
+pub fn content_file(hash: &[u8]) -> String {
+    return format!("{}/{}", CONTENT_DIR, hex::encode(hash));
 }
 
-pub fn content_dir(archive_dir: &Path) -> PathBuf {
-    return archive_dir.to_path_buf().join(CONTENT_DIR);
+/// Like [`content_file`], but fanned out by the first two bytes: `content/ab/cd/abcd...`.
+pub fn content_file_sharded(hash: &[u8]) -> String {
+    let hash_str = hex::encode(hash);
+
+    return format!("{}/{}/{}/{}", CONTENT_DIR, &hash_str[0..2], &hash_str[2..4], hash_str);
 }
 
-pub fn content_file(archive_dir: &Path, hash: &[u8]) -> PathBuf {
-    let mut hash_str = String::new();
-    for byte in hash {
-        hash_str.push_str(&format!("{:02x}", *byte));
-    }
+pub fn pack_file(pack: &str) -> String {
+    return format!("{}/{}.{}", PACKS_DIR, pack, PACK_EXTENSION);
+}
+
+pub fn pack_idx_file(pack: &str) -> String {
+    return format!("{}/{}.{}", PACKS_DIR, pack, PACK_IDX_EXTENSION);
+}
+
+pub fn pack_index_file() -> String {
+    return format!("{}/{}", PACKS_DIR, PACK_INDEX_FILE);
+}
 
-    return content_dir(archive_dir).join(hash_str);
+/// Lock of a process which only reads the archive; many of them can exist.
+pub fn shared_lock_file(name: &str) -> String {
+    return format!("{}/{}", LOCKS_DIR, name);
 }
 
-pub fn channel_dir(archive_dir: &Path, channel: &str) -> PathBuf {
-    return archive_dir.to_path_buf().join(CHANNEL_DIR).join(channel);
+pub fn channel_dir(channel: &str) -> String {
+    return format!("{}/{}", CHANNEL_DIR, channel);
 }
 
-pub fn channel_file(archive_dir: &Path, channel: &str, channel_rev: &str) -> PathBuf {
-    return channel_dir(archive_dir, channel).join(format!("{}", channel_rev));
+pub fn channel_file(channel: &str, channel_rev: &str) -> String {
+    return format!("{}/{}", channel_dir(channel), channel_rev);
 }
 
-pub fn lock_file(archive_dir: &Path) -> PathBuf {
-    return archive_dir.to_path_buf().join(LOCK_FILE);
+/// Last part of a key, e.g. the name of a revision.
+pub fn key_name(key: &str) -> &str {
+    return key.rsplit('/').next().unwrap_or(key);
 }
 
-pub fn next_channel_file(archive_dir: &Path, channel: &str) -> anyhow::Result<PathBuf> {
+pub fn next_channel_file(channel: &str) -> String {
     let t = Utc::now();
     let rn = StdRng::from_os_rng().next_u64();
 
-    let file_name = format!("{}{}{}", t.year(), t.month(), t.day())
+    let file_name = format!("{:0>4}{:0>2}{:0>2}", t.year(), t.month(), t.day())
         + &format!("_{:0>2}{:0>2}", t.hour(), t.minute())
         + &format!("_{:0>2}", t.second())
         + &format!("_{:016x}", rn);
 
-    return Ok(channel_dir(archive_dir, channel).join(file_name));
-}
-
-fn content_paths(archive_dir: &Path) -> anyhow::Result<DirWalk> {
-    return Ok(DirWalk::new(DirWalkParameters {
-        root_dir: content_dir(archive_dir),
-        recursive: false,
-        filter: Some(|path| {
-            return misc_helper::is_file(path);
-        }),
-    })
-    .expect("archive missing"));
-}
-
-pub fn channel_rev_paths(archive_dir: &Path, channel: &str) -> anyhow::Result<DirWalk> {
-    return Ok(DirWalk::new(DirWalkParameters {
-        root_dir: channel_dir(archive_dir, channel),
-        recursive: false,
-        filter: Some(|path| {
-            return misc_helper::is_file(path);
-        }),
-    })
-    .expect("archive missing"));
-}
-
-pub fn channel_paths(archive_dir: &Path) -> anyhow::Result<DirWalk> {
-    return Ok(DirWalk::new(DirWalkParameters {
-        root_dir: archive_dir.to_path_buf().join(CHANNEL_DIR),
-        recursive: false,
-        filter: Some(|path| {
-            return misc_helper::is_dir(path);
-        }),
-    })
-    .expect("archive missing"));
-}
-
-pub fn channel_rev_last(archive_dir: &Path, channel: &str) -> anyhow::Result<PathBuf> {
-    let mut latest_rev = None;
-
-    for rev in channel_rev_paths(archive_dir, channel)? {
-        if let Some(latest_rev) = &mut latest_rev {
-            if *rev > *latest_rev {
-                *latest_rev = rev;
-            }
-        } else {
-            latest_rev = Some(rev);
+    return channel_file(channel, &file_name);
+}
+
+pub fn content_paths(backend: &dyn Backend) -> anyhow::Result<Vec<String>> {
+    return backend.list(CONTENT_DIR);
+}
+
+/// Keys of all revisions of `channel`; empty when it has none.
+pub fn channel_rev_paths(backend: &dyn Backend, channel: &str) -> anyhow::Result<Vec<String>> {
+    let channel_dir = channel_dir(channel);
+
+    return Ok(backend.list(&channel_dir)?
+        .into_iter()
+        .filter(|key| key[channel_dir.len() + 1..].find('/').is_none())
+        .collect());
+}
+
+/// Names of all channels with at least one revision.
+pub fn channel_names(backend: &dyn Backend) -> anyhow::Result<Vec<String>> {
+    let mut ret: Vec<String> = Vec::new();
+
+    for key in backend.list(CHANNEL_DIR)? {
+        let mut parts = key[CHANNEL_DIR.len() + 1..].split('/');
+        let (Some(channel), Some(_)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        if !ret.iter().any(|x| x == channel) {
+            ret.push(channel.to_owned());
         }
     }
 
-    return latest_rev.ok_or(anyhow!("cannot get latest revision in channel {}", channel));
+    ret.sort();
+    return Ok(ret);
 }
 
+/// Revisions of `channel` with their [`channel_rev_time`], oldest first.
+///
+/// Names cannot be compared as they are; older ones do not pad month and day.
+/// Revisions of the same second are ordered by when their files were written,
+/// then by name; only those need their file time.
+pub fn channel_revs_ordered(backend: &dyn Backend, channel: &str) -> anyhow::Result<Vec<(DateTime<Utc>, String)>> {
+    let mut revs: Vec<(DateTime<Utc>, Option<SystemTime>, String)> = Vec::new();
+    for rev_path in channel_rev_paths(backend, channel)? {
+        revs.push((channel_rev_time(backend, &rev_path)?, None, rev_path));
+    }
+
+    let mut per_second: HashMap<DateTime<Utc>, usize> = HashMap::new();
+    for (time, _, _) in &revs {
+        *per_second.entry(*time).or_default() += 1;
+    }
+    for (time, modified, rev_path) in revs.iter_mut() {
+        if per_second[time] > 1 {
+            *modified = Some(backend.stat(rev_path)
+                .with_context(|| format!("cannot get time of revision {}", rev_path))?
+                .modified);
+        }
+    }
+
+    revs.sort();
+    return Ok(revs.into_iter().map(|(time, _, rev_path)| (time, rev_path)).collect());
+}
 
+/// Newest revision of `channel` in the order of [`channel_revs_ordered`].
+pub fn channel_rev_last(backend: &dyn Backend, channel: &str) -> anyhow::Result<String> {
+    return channel_revs_ordered(backend, channel)?
+        .pop()
+        .map(|(_, rev_path)| rev_path)
+        .ok_or(anyhow!("cannot get latest revision in channel {}", channel));
+}
+
+/// Creation time of a revision, taken from the name given by [`next_channel_file`].
+///
+/// Older archives did not pad month and day, so their names can be ambiguous.
+/// For those the modification time of the revision file is used instead.
+pub fn channel_rev_time(backend: &dyn Backend, rev_path: &str) -> anyhow::Result<DateTime<Utc>> {
+    let name = key_name(rev_path);
+
+    let mut parts = name.split('_');
+    let date = parts.next().unwrap_or_default();
+    let hour_minute = parts.next().unwrap_or_default();
+    let second = parts.next().unwrap_or_default();
+
+    if date.len() == 8 && hour_minute.len() == 4 && second.len() == 2 {
+        let parsed = NaiveDateTime::parse_from_str(
+            &format!("{}{}{}", date, hour_minute, second),
+            "%Y%m%d%H%M%S",
+        );
+        if let Ok(parsed) = parsed {
+            return Ok(parsed.and_utc());
+        }
+    }
+
+    let modified = backend
+        .stat(rev_path)
+        .with_context(|| format!("cannot get time of revision {}", rev_path))?
+        .modified;
+
+    return Ok(DateTime::<Utc>::from(modified));
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use serde::Serialize;
+use super::channel_reader::{ChannelItemKind, ChannelReader, ChannelReaderItem, ChannelReaderOptions};
+use super::session::{BackupSession, ToSession};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffChange {
+    Added,
+    Removed,
+    /// the content, the target or the kind of the item changed
+    Modified,
+    /// a file with the same hash was removed under another path
+    Renamed,
+    /// only metadata like mode, owner or mtime changed
+    Meta,
+}
+
+impl DiffChange {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            DiffChange::Added => "added",
+            DiffChange::Removed => "removed",
+            DiffChange::Modified => "modified",
+            DiffChange::Renamed => "renamed",
+            DiffChange::Meta => "meta",
+        };
+    }
+}
+
+#[derive(Serialize)]
+pub struct DiffEntry {
+    pub change: DiffChange,
+    pub path: String,
+    /// the old path of a renamed file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// names of the changed metadata
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub meta: Vec<&'static str>,
+}
+
+#[derive(Serialize, Default)]
+pub struct DiffStat {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+    pub renamed: usize,
+    pub meta: usize,
+    /// size of the added and modified files in the new revision
+    pub bytes_added: u64,
+    /// size of the removed and modified files in the old revision
+    pub bytes_removed: u64,
+}
+
+#[derive(Serialize, Default)]
+pub struct RevisionDiff {
+    pub entries: Vec<DiffEntry>,
+    pub stat: DiffStat,
+}
+
+type Items = BTreeMap<PathBuf, ChannelReaderItem>;
+
+fn read_items(session: BackupSession, channel: &str, rev: &str) -> anyhow::Result<(BackupSession, Items)> {
+    let mut reader = ChannelReader::new(session, ChannelReaderOptions {
+        channel: channel.to_owned(),
+        entry: Some(rev.to_owned()),
+    })?;
+
+    let mut items = Items::new();
+    for item in &mut reader {
+        let item = item?;
+        items.insert(item.relative_path.clone(), item);
+    }
+
+    return Ok((reader.to_session(), items));
+}
+
+fn file_size(item: &ChannelReaderItem) -> u64 {
+    return match &item.kind {
+        ChannelItemKind::File { signature: Some(signature), .. } => signature.size,
+        _ => 0,
+    };
+}
+
+/// Whether both items have the same kind and content; metadata is not compared.
+fn same_content(old: &ChannelReaderItem, new: &ChannelReaderItem) -> bool {
+    return match (&old.kind, &new.kind) {
+        (ChannelItemKind::File { checksum: old, .. }, ChannelItemKind::File { checksum: new, .. }) => old == new,
+        (ChannelItemKind::Dir, ChannelItemKind::Dir) => true,
+        (ChannelItemKind::Symlink { target: old }, ChannelItemKind::Symlink { target: new }) => old == new,
+        (ChannelItemKind::Hardlink { target: old }, ChannelItemKind::Hardlink { target: new }) => old == new,
+        (ChannelItemKind::Special { kind: old_kind, rdev: old_rdev }, ChannelItemKind::Special { kind: new_kind, rdev: new_rdev }) =>
+            old_kind == new_kind && old_rdev == new_rdev,
+        _ => false,
+    };
+}
+
+/// Names of the metadata which differs; the atime changes on every read, so it is left out.
+fn changed_meta(old: &ChannelReaderItem, new: &ChannelReaderItem) -> Vec<&'static str> {
+    let mut ret = Vec::new();
+    if old.meta.mode != new.meta.mode {
+        ret.push("mode");
+    }
+    if old.meta.uid != new.meta.uid || old.meta.gid != new.meta.gid {
+        ret.push("owner");
+    }
+    if old.meta.mtime != new.meta.mtime {
+        ret.push("mtime");
+    }
+    if old.meta.xattrs != new.meta.xattrs {
+        ret.push("xattrs");
+    }
+    return ret;
+}
+
+/// Compare the revisions `old_rev` and `new_rev` of `channel`.
+///
+/// A removed and an added file with the same hash are reported as rename.
+pub fn diff_revisions(session: BackupSession, channel: &str, old_rev: &str, new_rev: &str) -> anyhow::Result<(BackupSession, RevisionDiff)> {
+    let (session, old_items) = read_items(session, channel, old_rev)?;
+    let (session, new_items) = read_items(session, channel, new_rev)?;
+
+    let mut diff = RevisionDiff::default();
+    let mut removed: Vec<&ChannelReaderItem> = old_items.iter()
+        .filter(|(path, _)| !new_items.contains_key(*path))
+        .map(|(_, item)| item)
+        .collect();
+
+    for (path, new) in &new_items {
+        let path_str = path.to_string_lossy().to_string();
+
+        let Some(old) = old_items.get(path) else {
+            //each removed file is the source of one rename at most
+            let renamed_from = match &new.kind {
+                ChannelItemKind::File { checksum, .. } => removed.iter().position(|x| {
+                    matches!(&x.kind, ChannelItemKind::File { checksum: old, .. } if old == checksum)
+                }),
+                _ => None,
+            };
+
+            if let Some(index) = renamed_from {
+                let old = removed.remove(index);
+                diff.stat.renamed += 1;
+                diff.entries.push(DiffEntry {
+                    change: DiffChange::Renamed,
+                    path: path_str,
+                    from: Some(old.relative_path.to_string_lossy().to_string()),
+                    meta: changed_meta(old, new),
+                });
+                continue;
+            }
+
+            diff.stat.added += 1;
+            diff.stat.bytes_added += file_size(new);
+            diff.entries.push(DiffEntry {
+                change: DiffChange::Added,
+                path: path_str,
+                from: None,
+                meta: Vec::new(),
+            });
+            continue;
+        };
+
+        let meta = changed_meta(old, new);
+        let change = match same_content(old, new) {
+            false => DiffChange::Modified,
+            true if !meta.is_empty() => DiffChange::Meta,
+            true => continue,
+        };
+
+        if change == DiffChange::Modified {
+            diff.stat.modified += 1;
+            diff.stat.bytes_added += file_size(new);
+            diff.stat.bytes_removed += file_size(old);
+        } else {
+            diff.stat.meta += 1;
+        }
+
+        diff.entries.push(DiffEntry {
+            change: change,
+            path: path_str,
+            from: None,
+            meta: meta,
+        });
+    }
+
+    for old in removed {
+        diff.stat.removed += 1;
+        diff.stat.bytes_removed += file_size(old);
+        diff.entries.push(DiffEntry {
+            change: DiffChange::Removed,
+            path: old.relative_path.to_string_lossy().to_string(),
+            from: None,
+            meta: Vec::new(),
+        });
+    }
+
+    diff.entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    return Ok((session, diff));
+}
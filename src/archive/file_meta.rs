@@ -0,0 +1,328 @@
+use std::fs::{self, Metadata};
+use std::io::ErrorKind;
+use std::path::Path;
+use anyhow::{anyhow, Context};
+use filetime::FileTime;
+use super::defs;
+
+/// File attributes stored next to the `file:`/`dir:` entry of a revision.
+///
+/// Every member is optional so revisions written before it was introduced,
+/// or on platforms without the attribute, can still be read.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct FileMeta {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mtime: Option<FileTime>,
+    pub atime: Option<FileTime>,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Size, modification time and inode of a regular file.
+///
+/// When all of them are unchanged since the last backup, the file is assumed
+/// to be unchanged and its recorded hash is reused.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileSignature {
+    pub size: u64,
+    pub mtime: FileTime,
+    pub inode: u64,
+}
+
+impl FileSignature {
+    pub fn from_metadata(metadata: &Metadata) -> FileSignature {
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            metadata.ino()
+        };
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        return FileSignature {
+            size: metadata.len(),
+            mtime: FileTime::from_last_modification_time(metadata),
+            inode: inode,
+        };
+    }
+}
+
+/// Files which are neither regular files, dirs nor links.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpecialKind {
+    Fifo,
+    CharDevice,
+    BlockDevice,
+}
+
+impl SpecialKind {
+    pub fn from_metadata(metadata: &Metadata) -> Option<SpecialKind> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            let file_type = metadata.file_type();
+
+            if file_type.is_fifo() {
+                return Some(SpecialKind::Fifo);
+            } else if file_type.is_char_device() {
+                return Some(SpecialKind::CharDevice);
+            } else if file_type.is_block_device() {
+                return Some(SpecialKind::BlockDevice);
+            }
+        }
+
+        return None;
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            SpecialKind::Fifo => "fifo",
+            SpecialKind::CharDevice => "char",
+            SpecialKind::BlockDevice => "block",
+        };
+    }
+
+    pub fn from_str(value: &str) -> anyhow::Result<SpecialKind> {
+        return match value {
+            "fifo" => Ok(SpecialKind::Fifo),
+            "char" => Ok(SpecialKind::CharDevice),
+            "block" => Ok(SpecialKind::BlockDevice),
+            _ => Err(anyhow!("unknown special file type {}", value)),
+        };
+    }
+
+    /// Create the node; device nodes usually need root privileges.
+    pub fn create(&self, path: &Path, mode: u32, rdev: u64) -> anyhow::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+            let file_type = match self {
+                SpecialKind::Fifo => libc::S_IFIFO,
+                SpecialKind::CharDevice => libc::S_IFCHR,
+                SpecialKind::BlockDevice => libc::S_IFBLK,
+            };
+
+            let result = unsafe {
+                libc::mknod(
+                    c_path.as_ptr(),
+                    file_type | (mode & 0o7777) as libc::mode_t,
+                    rdev as libc::dev_t,
+                )
+            };
+
+            if result != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("cannot create {} {}", self.as_str(), path.to_string_lossy()));
+            }
+
+            return Ok(());
+        }
+
+        #[cfg(not(unix))]
+        return Err(anyhow!("special files are not supported on this platform"));
+    }
+}
+
+/// Identifies the inode of a file so hardlinks can be detected.
+pub fn inode_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if metadata.nlink() > 1 {
+            return Some((metadata.dev(), metadata.ino()));
+        }
+    }
+
+    return None;
+}
+
+fn time_to_string(time: &FileTime) -> String {
+    return format!("{}.{:09}", time.unix_seconds(), time.nanoseconds());
+}
+
+fn time_from_string(value: &str) -> anyhow::Result<FileTime> {
+    let (seconds, nanoseconds) = value.split_once('.').unwrap_or((value, "0"));
+    return Ok(FileTime::from_unix_time(seconds.parse()?, nanoseconds.parse()?));
+}
+
+/// Open `path` to get at its xattrs through the descriptor instead of the path.
+///
+/// Symlinks and special files are not opened; their xattrs are skipped, and a
+/// fifo would block. Without `follow_symlinks` a symlink which replaced the
+/// file in the meantime is not followed either.
+#[cfg(unix)]
+fn open_for_xattrs(path: &Path, metadata: &Metadata, follow_symlinks: bool) -> Option<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if !metadata.is_file() && !metadata.is_dir() {
+        return None;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.read(true);
+    if !follow_symlinks {
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+
+    return options.open(path).ok();
+}
+
+impl FileMeta {
+    /// `metadata` is the one of the target when the backup follows symlinks.
+    pub fn from_metadata(path: &Path, metadata: &Metadata, follow_symlinks: bool) -> FileMeta {
+        let mut meta = FileMeta {
+            mtime: Some(FileTime::from_last_modification_time(metadata)),
+            atime: Some(FileTime::from_last_access_time(metadata)),
+            ..Default::default()
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            meta.mode = Some(metadata.mode() & 0o7777);
+            meta.uid = Some(metadata.uid());
+            meta.gid = Some(metadata.gid());
+
+            use xattr::FileExt;
+
+            if let Some(file) = open_for_xattrs(path, metadata, follow_symlinks) {
+                for name in file.list_xattr().into_iter().flatten() {
+                    if let Ok(Some(value)) = file.get_xattr(&name) {
+                        meta.xattrs.push((name.to_string_lossy().to_string(), value));
+                    }
+                }
+            }
+        }
+
+        return meta;
+    }
+
+    /// Key value pairs in the order they are written to a revision.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut ret = Vec::new();
+
+        if let Some(mode) = self.mode {
+            ret.push((defs::keys::MODE, format!("{:o}", mode)));
+        }
+        if let Some(uid) = self.uid {
+            ret.push((defs::keys::UID, uid.to_string()));
+        }
+        if let Some(gid) = self.gid {
+            ret.push((defs::keys::GID, gid.to_string()));
+        }
+        if let Some(mtime) = &self.mtime {
+            ret.push((defs::keys::MTIME, time_to_string(mtime)));
+        }
+        if let Some(atime) = &self.atime {
+            ret.push((defs::keys::ATIME, time_to_string(atime)));
+        }
+        for (name, value) in &self.xattrs {
+            ret.push((defs::keys::XATTR, format!("{}={}", name, hex::encode(value))));
+        }
+
+        return ret;
+    }
+
+    /// Take over a single entry of a revision; returns false for unrelated keys.
+    pub fn parse_entry(&mut self, key: &str, value: &str) -> anyhow::Result<bool> {
+        let context = || format!("invalid {} entry {}", key, value);
+
+        match key {
+            defs::keys::MODE => self.mode = Some(u32::from_str_radix(value, 8).with_context(context)?),
+            defs::keys::UID => self.uid = Some(value.parse().with_context(context)?),
+            defs::keys::GID => self.gid = Some(value.parse().with_context(context)?),
+            defs::keys::MTIME => self.mtime = Some(time_from_string(value).with_context(context)?),
+            defs::keys::ATIME => self.atime = Some(time_from_string(value).with_context(context)?),
+            defs::keys::XATTR => {
+                let (name, data) = value.rsplit_once('=').ok_or(anyhow!(context()))?;
+                self.xattrs.push((name.to_owned(), hex::decode(data).with_context(context)?));
+            }
+            _ => return Ok(false),
+        }
+
+        return Ok(true);
+    }
+
+    /// Apply the attributes to a restored file or dir.
+    ///
+    /// Ownership is best-effort: without the privilege to change it the owner
+    /// of the restoring user is kept. Times are applied last because setting
+    /// the other attributes may touch them.
+    pub fn apply(&self, path: &Path) -> anyhow::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            use xattr::FileExt;
+
+            if !self.xattrs.is_empty() {
+                let file = fs::symlink_metadata(path)
+                    .ok()
+                    .and_then(|metadata| open_for_xattrs(path, &metadata, false));
+                for (name, value) in &self.xattrs {
+                    let result = match &file {
+                        Some(file) => file.set_xattr(name, value),
+                        None => Err(std::io::Error::other("neither a file nor a dir")),
+                    };
+                    if let Err(err) = result {
+                        println!("cannot set xattr {} on {}: {}", name, path.to_string_lossy(), err);
+                    }
+                }
+            }
+
+            if self.uid.is_some() || self.gid.is_some() {
+                match std::os::unix::fs::chown(path, self.uid, self.gid) {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == ErrorKind::PermissionDenied => {}
+                    Err(err) => println!("cannot change owner of {}: {}", path.to_string_lossy(), err),
+                }
+            }
+
+            if let Some(mode) = self.mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                    .with_context(|| format!("cannot set mode of {}", path.to_string_lossy()))?;
+            }
+        }
+
+        //set_file_times opens the file, which blocks on a fifo; the symlink variant only uses the path
+        if self.mtime.is_some() || self.atime.is_some() {
+            let metadata = fs::metadata(path)?;
+            filetime::set_symlink_file_times(
+                path,
+                self.atime.unwrap_or_else(|| FileTime::from_last_access_time(&metadata)),
+                self.mtime.unwrap_or_else(|| FileTime::from_last_modification_time(&metadata)),
+            )
+            .with_context(|| format!("cannot set times of {}", path.to_string_lossy()))?;
+        }
+
+        return Ok(());
+    }
+
+    /// Like [`FileMeta::apply`] but for the link itself instead of its target.
+    pub fn apply_symlink(&self, path: &Path) -> anyhow::Result<()> {
+        #[cfg(unix)]
+        {
+            if self.uid.is_some() || self.gid.is_some() {
+                match std::os::unix::fs::lchown(path, self.uid, self.gid) {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == ErrorKind::PermissionDenied => {}
+                    Err(err) => println!("cannot change owner of {}: {}", path.to_string_lossy(), err),
+                }
+            }
+        }
+
+        if self.mtime.is_some() || self.atime.is_some() {
+            let metadata = fs::symlink_metadata(path)?;
+            filetime::set_symlink_file_times(
+                path,
+                self.atime.unwrap_or_else(|| FileTime::from_last_access_time(&metadata)),
+                self.mtime.unwrap_or_else(|| FileTime::from_last_modification_time(&metadata)),
+            )
+            .with_context(|| format!("cannot set times of {}", path.to_string_lossy()))?;
+        }
+
+        return Ok(());
+    }
+}
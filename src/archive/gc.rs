@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use anyhow::{anyhow, Context};
+use super::defs;
+use super::content_store::StoredContent;
+use super::channel_reader::{ChannelReader, ChannelReaderOptions};
+use super::session::{BackupSession, ToSession};
+
+#[derive(Default)]
+pub struct GcReport {
+    pub referenced: usize,
+    pub removed: Vec<StoredContent>,
+    pub removed_bytes: u64,
+    /// keys of temporary objects of writers which crashed
+    pub removed_temp: Vec<String>,
+}
+
+fn unreadable_revision(rev_path: &str) -> String {
+    return format!(
+        "cannot read revision {}; no content is removed while a revision is unreadable. \
+        Check it with verify; if it cannot be repaired, e.g. by a sync from another archive, \
+        delete the revision and run gc again",
+        rev_path
+    );
+}
+
+/// Collect the ids of the content referenced by any revision of any channel.
+///
+/// Fails when a single revision cannot be read, so that a damaged revision never
+/// causes its content to be treated as garbage.
+pub fn referenced_content(session: BackupSession) -> anyhow::Result<(BackupSession, HashSet<String>)> {
+    let mut referenced = HashSet::new();
+    let mut session = session;
+
+    for channel in session.channel_names()? {
+        for rev_path in defs::channel_rev_paths(session.backend().as_ref(), &channel)? {
+            let entry = defs::key_name(&rev_path).to_owned();
+
+            let mut channel_reader = ChannelReader::new(session, ChannelReaderOptions {
+                channel: channel.clone(),
+                entry: Some(entry),
+            })
+            .with_context(|| unreadable_revision(&rev_path))?;
+
+            for item in &mut channel_reader {
+                let item = item
+                    .with_context(|| unreadable_revision(&rev_path))?;
+                for content in item.contents() {
+                    referenced.insert(content.to_hex());
+                }
+            }
+
+            session = channel_reader.to_session();
+        }
+    }
+
+    return Ok((session, referenced));
+}
+
+/// Remove all content which is not referenced by any revision, and the
+/// temporary objects of interrupted writers.
+///
+/// The session holds the archive lock for the whole run, so no backup can add
+/// new references in between. All revisions are read before the first blob is
+/// removed; an interrupted run only leaves garbage behind, never a dangling reference.
+pub fn collect_garbage(session: BackupSession, dry_run: bool) -> anyhow::Result<(BackupSession, GcReport)> {
+    let (mut session, referenced) = referenced_content(session)?;
+    let mut report = GcReport {
+        referenced: referenced.len(),
+        ..Default::default()
+    };
+
+    //sizes are taken before the blobs are gone
+    let store = session.content_store_mut();
+    for content in store.list()? {
+        if !referenced.contains(&content.id) {
+            report.removed_bytes += store.stored_size(&content)?;
+        }
+    }
+
+    report.removed = store.retain(&referenced, dry_run)?;
+
+    //the exclusive lock keeps out every writer; so no temporary object is still in use
+    let backend = session.backend().clone();
+    for key in backend.list_temp("")? {
+        if !dry_run {
+            backend.delete(&key)?;
+        }
+        report.removed_temp.push(key);
+    }
+
+    return Ok((session, report));
+}
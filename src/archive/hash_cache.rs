@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context};
+use crate::checksum::{HashAlgo, HashResult};
+use super::defs;
+use super::channel_reader::{ChannelItemKind, ChannelReader, ChannelReaderOptions};
+use super::file_meta::FileSignature;
+use super::session::{BackupSession, ToSession};
+
+/// Hashes of the files in the last revision of a channel, so a backup only
+/// has to read files which changed since then.
+#[derive(Default)]
+pub struct HashCache {
+    entries: HashMap<PathBuf, (FileSignature, CachedFile)>,
+}
+
+pub struct CachedFile {
+    pub checksum: HashResult,
+    /// `None` when the file was stored whole
+    pub chunks: Option<Vec<HashResult>>,
+}
+
+impl HashCache {
+    /// Read the last revision of `channel`; a channel without revisions gives an empty cache.
+    pub fn from_last_revision(session: BackupSession, channel: &str) -> anyhow::Result<(BackupSession, HashCache)> {
+        let mut cache = HashCache::default();
+
+        let Ok(rev_path) = defs::channel_rev_last(session.backend().as_ref(), channel) else {
+            return Ok((session, cache));
+        };
+
+        let mut channel_reader = ChannelReader::new(session, ChannelReaderOptions {
+            channel: channel.to_owned(),
+            entry: None,
+        })
+        .with_context(|| format!("cannot open revision {}", rev_path))?;
+
+        //an unreadable entry is only a cache miss
+        let items: Vec<_> = (&mut channel_reader).flatten().collect();
+        let session = channel_reader.to_session();
+        let store = session.content_store();
+
+        for item in items {
+            if let ChannelItemKind::File { checksum, contents, chunked, signature: Some(signature) } = item.kind {
+                //chunks are not read again, so they must still be there
+                if chunked {
+                    let mut stored = true;
+                    for content in &contents {
+                        if !store.contains(content)? {
+                            stored = false;
+                            break;
+                        }
+                    }
+                    if !stored {
+                        continue;
+                    }
+                }
+                let chunks = match chunked {
+                    true => Some(contents),
+                    false => None,
+                };
+                cache.entries.insert(item.relative_path, (signature, CachedFile {
+                    checksum: checksum,
+                    chunks: chunks,
+                }));
+            }
+        }
+
+        return Ok((session, cache));
+    }
+
+    /// The recorded hashes when the file is unchanged and was hashed with `algo`.
+    pub fn get(&self, relative_path: &Path, signature: &FileSignature, algo: HashAlgo) -> Option<&CachedFile> {
+        let (cached_signature, cached) = self.entries.get(relative_path)?;
+
+        if cached_signature != signature || cached.checksum.algo() != algo {
+            return None;
+        }
+
+        return Some(cached);
+    }
+}
@@ -1,11 +1,35 @@
+mod backend;
 mod defs;
+pub use defs::key_name;
 mod session;
 mod channel_reader;
 mod channel_writer;
+mod chunker;
 mod content;
+mod content_store;
+mod crypto;
+mod file_meta;
+mod verify;
+mod gc;
+mod hash_cache;
+mod prune;
+mod sync;
+mod diff;
+mod status;
 
-pub use session::{BackupSession, ToSession, GetSession};
-pub use content::{ContentSettings, ContentCompression};
+pub use backend::{open_backend, Backend, LocalBackend, ObjectInfo, S3Backend, SftpBackend};
+pub use session::{break_lock, BackupSession, BreakLockReport, GetSession, LockInfo, LockMode, ToSession};
+pub use chunker::{write_chunked_file, ChunkedFile, ContentChunking};
+pub use content::{ContentAddressing, encode_content_file, write_content_file, ContentCompression, ContentReader, ContentSettings, ContentWriter};
+pub use content_store::{ContentLayout, ContentLocation, ContentPacking, ContentStore, PackLocation, StoredContent};
+pub use crypto::{decrypt_reader, ContentEncryption, ContentKey, EncryptWriter};
+pub use file_meta::{inode_id, FileMeta, FileSignature, SpecialKind};
+pub use hash_cache::{CachedFile, HashCache};
 pub use channel_reader::*;
 pub use channel_writer::*;
-
+pub use verify::{verify_all, VerifyProblem, VerifyReport};
+pub use gc::{collect_garbage, GcReport};
+pub use prune::{prune_channel, PruneReport, RetentionPolicy};
+pub use sync::{sync_archive, SyncMode, SyncOptions, SyncReport};
+pub use diff::{diff_revisions, DiffChange, DiffEntry, DiffStat, RevisionDiff};
+pub use status::{source_status, StatusChange, StatusEntry, StatusOptions, StatusReport};
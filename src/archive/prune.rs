@@ -0,0 +1,120 @@
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Datelike, Utc};
+use super::defs;
+use super::session::BackupSession;
+
+/// How many revisions to keep, similar to restic/borg.
+///
+/// A revision is kept when any of the rules selects it.
+#[derive(Default, Clone, Copy)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        return self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0;
+    }
+}
+
+pub struct PruneRev {
+    /// key of the revision
+    pub path: String,
+    pub time: DateTime<Utc>,
+    pub keep: bool,
+}
+
+#[derive(Default)]
+pub struct PruneReport {
+    pub revs: Vec<PruneRev>,
+}
+
+impl PruneReport {
+    pub fn kept(&self) -> impl Iterator<Item = &PruneRev> {
+        return self.revs.iter().filter(|x| x.keep);
+    }
+
+    pub fn removed(&self) -> impl Iterator<Item = &PruneRev> {
+        return self.revs.iter().filter(|x| !x.keep);
+    }
+}
+
+/// Keep the newest revision of each of the `count` most recent buckets.
+///
+/// `revs` must be sorted newest first.
+fn keep_buckets<K: PartialEq>(revs: &mut [PruneRev], count: usize, bucket: impl Fn(&DateTime<Utc>) -> K) {
+    let mut last_bucket: Option<K> = None;
+    let mut kept = 0usize;
+
+    for rev in revs.iter_mut() {
+        if kept >= count {
+            break;
+        }
+
+        let current = bucket(&rev.time);
+        if last_bucket.as_ref().is_some_and(|x| *x == current) {
+            continue;
+        }
+
+        rev.keep = true;
+        last_bucket = Some(current);
+        kept += 1;
+    }
+}
+
+/// Mark the revisions the policy keeps; `revs` must be sorted newest first.
+pub fn select_revs(revs: &mut [PruneRev], policy: &RetentionPolicy) {
+    for rev in revs.iter_mut().take(policy.keep_last) {
+        rev.keep = true;
+    }
+
+    keep_buckets(revs, policy.keep_daily, |t| t.date_naive());
+    keep_buckets(revs, policy.keep_weekly, |t| t.iso_week());
+    keep_buckets(revs, policy.keep_monthly, |t| (t.year(), t.month()));
+}
+
+/// Remove the revisions of a channel which are not selected by the policy.
+///
+/// Content is not touched; run garbage collection afterwards to reclaim space.
+pub fn prune_channel(
+    session: &BackupSession,
+    channel: &str,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> anyhow::Result<PruneReport> {
+    if policy.is_empty() {
+        return Err(anyhow!("refuse to prune without any keep rule; this would remove all revisions"));
+    }
+
+    if !session.channel_names()?.iter().any(|x| x == channel) {
+        return Err(anyhow!("channel {} does not exist", channel));
+    }
+
+    let mut report = PruneReport::default();
+
+    //newest first in the order restore takes the latest from
+    for (time, rev_path) in defs::channel_revs_ordered(session.backend().as_ref(), channel)?.into_iter().rev() {
+        report.revs.push(PruneRev {
+            time: time,
+            path: rev_path,
+            keep: false,
+        });
+    }
+
+    select_revs(&mut report.revs, policy);
+
+    if !dry_run {
+        for rev in report.removed() {
+            session.backend().delete(&rev.path)
+                .with_context(|| format!("cannot remove revision {}", rev.path))?;
+        }
+    }
+
+    return Ok(report);
+}
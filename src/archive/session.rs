@@ -1,108 +1,480 @@
-use std::{fs, path::{Path, PathBuf}};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
 use anyhow::{anyhow, bail, Context};
-use crate::misc_helper::{self, is_dir, is_file_or_dir};
-use super::{defs::*, ContentSettings};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::checksum::{self, HashAlgo, HashResult, Hasher};
+use crate::misc_helper;
+use super::backend::{open_backend, temp_key, Backend, ObjectWriter};
+use super::{defs::*, ContentAddressing, ContentSettings};
+use super::content_store::{ContentLayout, ContentStore};
+use super::crypto::{self, ContentEncryption, ContentKey, EncryptWriter, WrappedKey};
+
+const SECRET_SIZE: usize = 32;
 
 pub struct BackupSession {
-    pub archive_dir: PathBuf,
+    backend: Arc<dyn Backend>,
     pub settings: ContentSettings,
+    key: Option<ContentKey>,
+    secret: Option<Vec<u8>>,
+    store: ContentStore,
    
     #[allow(dead_code)]
     pub lock: ArchiveLock,
 }
 
 impl BackupSession {
-    pub fn init_session(archive_dir: &Path, settings: ContentSettings) -> anyhow::Result<()> {
+    pub fn init_session(archive: &str, settings: ContentSettings, password: Option<&str>) -> anyhow::Result<()> {
+        let backend = open_backend(archive)?;
         let err_msg = || {
             return format!("init archive failed");
         };
     
-        if is_file_or_dir(&archive_dir) {
-            return Err(anyhow!(err_msg()));
+        if backend.exists(SETTINGS_FILE).with_context(|| err_msg())? {
+            return Err(anyhow!("{}; archive exists already", err_msg()));
+        }
+
+        let key = match settings.encryption {
+            ContentEncryption::None => None,
+            _ => Some(ContentKey::generate()),
+        };
+        //anyone reading a plain secret could compute the content names
+        if settings.addressing == ContentAddressing::Keyed && key.is_none() {
+            bail!("{}; private content names need an encrypted archive", err_msg());
+        }
+
+        let wrapped_key = match &key {
+            Some(key) => {
+                let password = password.ok_or(anyhow!("an encrypted archive needs a password"))?;
+                Some(key.wrap(password)?)
+            }
+            None => None,
+        };
+
+        if let Some(wrapped_key) = wrapped_key {
+            backend.put_data(KEY_FILE, serde_json::to_string_pretty(&wrapped_key)?.as_bytes())
+                .with_context(||{ anyhow!("cannot write key file") })?;
         }
-    
-        fs::create_dir(&archive_dir)
-            .with_context(|| err_msg())?;
-        fs::create_dir(&archive_dir.to_path_buf().join(CHANNEL_DIR))
-            .with_context(|| err_msg())?;
-        fs::create_dir(content_dir(&archive_dir.to_path_buf()))
-            .with_context(|| err_msg())?;
-    
-        fs::write(settings_file(&archive_dir), serde_json::to_string_pretty(&settings)?)
-            .with_context(||{ anyhow!("cannot write settings file {}", 
-                settings_file(&archive_dir).to_string_lossy()) })?;
+
+        if let (ContentAddressing::Keyed, Some(key)) = (settings.addressing, &key) {
+            let secret = hex::encode(crypto::random_bytes(SECRET_SIZE));
+            let writer = backend.put(SECRET_FILE)
+                .with_context(||{ anyhow!("cannot write secret file") })?;
+            let mut writer = EncryptWriter::new(writer, key)?;
+            writer.write_all(secret.as_bytes())?;
+            writer.finish()?.commit()?;
+        }
+
+        //the settings mark a complete archive; so they come last
+        backend.put_data(SETTINGS_FILE, serde_json::to_string_pretty(&settings)?.as_bytes())
+            .with_context(||{ anyhow!("cannot write settings file") })?;
 
         return Ok(());
     }
 
-    pub fn new(archive_dir: &Path) -> anyhow::Result<BackupSession> {
-        misc_helper::is_dir_expected(&archive_dir, || "archive dir does not exist".into())?;
-        misc_helper::is_dir_expected(&archive_dir.to_path_buf().join(CHANNEL_DIR), || "channel dir does not exist".into())?;
-        misc_helper::is_dir_expected(&content_dir(&archive_dir.to_path_buf()), || "content dir does not exist".into())?;
+    /// Open the archive for writing; no other process may use it meanwhile.
+    pub fn new(archive: &str, password: Option<&str>) -> anyhow::Result<BackupSession> {
+        return BackupSession::open(archive, password, LockMode::Exclusive);
+    }
+
+    /// Open the archive for reading; other readers may use it too, but no writer.
+    pub fn new_shared(archive: &str, password: Option<&str>) -> anyhow::Result<BackupSession> {
+        return BackupSession::open(archive, password, LockMode::Shared);
+    }
+
+    fn open(archive: &str, password: Option<&str>, mode: LockMode) -> anyhow::Result<BackupSession> {
+        let backend = open_backend(archive)?;
+
+        if !backend.exists(SETTINGS_FILE)? {
+            bail!("archive {} does not exist", backend.location());
+        }
+
+        //the content store may repair its index, so lock first
+        let lock = ArchiveLock::new(backend.clone(), mode)?;
 
         let settings= {
-            let content = fs::read(settings_file(&archive_dir))
+            let content = backend.get_data(SETTINGS_FILE)
                 .with_context(||{anyhow!("cannot read settings file")})?;
             let content = String::from_utf8(content)
                 .with_context(||{anyhow!("settings file is not valid Utf-8")})?;
-            serde_json::from_str(&content)?
+            serde_json::from_str::<ContentSettings>(&content)?
+        };
+
+        let key = match settings.encryption {
+            ContentEncryption::None => None,
+            _ => {
+                let wrapped_key: WrappedKey = serde_json::from_slice(&backend.get_data(KEY_FILE)
+                    .with_context(||{anyhow!("cannot read key file")})?)?;
+                let password = password.ok_or(anyhow!("archive is encrypted; a password is needed"))?;
+                Some(ContentKey::unwrap(&wrapped_key, password)?)
+            }
+        };
+
+        let secret = match settings.addressing {
+            ContentAddressing::Plain => None,
+            ContentAddressing::Keyed => {
+                let file = backend.get(SECRET_FILE)
+                    .with_context(||{anyhow!("cannot read secret file")})?;
+                let mut secret = String::new();
+                crypto::decrypt_reader(file, key.as_ref())?.read_to_string(&mut secret)?;
+                Some(hex::decode(secret.trim()).with_context(||{anyhow!("secret file is invalid")})?)
+            }
         };
         
+        let store = ContentStore::open(backend.clone(), &settings, key.as_ref(), mode == LockMode::Exclusive)?;
+        
         return Ok(BackupSession {
-            archive_dir: archive_dir.to_owned(),
+            backend: backend,
             settings: settings,
-            lock: ArchiveLock::new(archive_dir.to_owned())?,
+            key: key,
+            secret: secret,
+            store: store,
+            lock: lock,
         });
     }
 
-    pub fn get_archive_dir(&self) -> &Path {
-        return &self.archive_dir;
+    pub fn backend(&self) -> &Arc<dyn Backend> {
+        return &self.backend;
     }
 
     pub fn get_settings(&self) -> &ContentSettings {
         return &self.settings;
     }
 
+    pub fn get_key(&self) -> Option<&ContentKey> {
+        return self.key.as_ref();
+    }
+
+    pub fn content_store(&self) -> &ContentStore {
+        return &self.store;
+    }
+
+    pub fn content_store_mut(&mut self) -> &mut ContentStore {
+        return &mut self.store;
+    }
+
+    /// Switch the archive to `layout` and move the existing loose content; returns the number of moved files.
+    ///
+    /// The settings are written first, so new content already uses the new layout
+    /// should the move be interrupted; readers accept both layouts.
+    pub fn migrate_layout(&mut self, layout: ContentLayout) -> anyhow::Result<usize> {
+        self.settings.layout = layout;
+        self.backend.put_data(SETTINGS_FILE, serde_json::to_string_pretty(&self.settings)?.as_bytes())
+            .with_context(||{ anyhow!("cannot write settings file") })?;
+
+        return self.store.migrate_layout(layout);
+    }
+
+    /// Hasher for the names of content files; keyed with the secret in keyed archives.
+    ///
+    /// New content uses the algorithm of the settings; older content may have been
+    /// written with another one, so it is given explicitly.
+    pub fn content_hasher(&self, algo: HashAlgo) -> Box<dyn Hasher> {
+        return match &self.secret {
+            Some(secret) => checksum::new_hasher_keyed(algo, secret),
+            None => checksum::new_hasher(algo),
+        };
+    }
+
+    /// Decompress `contents` one after another into the local file `dst` and
+    /// check each against its id while it streams.
+    ///
+    /// The data goes to a temp file next to `dst`, which replaces `dst` only when
+    /// every content is intact, or with `keep_corrupt` as far as it could be read;
+    /// otherwise an existing `dst` is left untouched. Corrupt content is returned
+    /// with the reason, so the caller decides what to report.
+    pub fn read_to_file(&self, contents: &[HashResult], dst: &Path, keep_corrupt: bool) -> anyhow::Result<Vec<anyhow::Error>> {
+        let mut temp_name = OsString::from(".");
+        temp_name.push(dst.file_name().unwrap_or_default());
+        temp_name.push(format!(".restore-{:016x}", rand::random::<u64>()));
+        let temp_path = dst.with_file_name(temp_name);
+
+        let corrupt = self.read_to_temp_file(contents, &temp_path);
+        let replace = match &corrupt {
+            Ok(corrupt) => corrupt.is_empty() || keep_corrupt,
+            Err(_) => false,
+        };
+        if !replace {
+            let _ = fs::remove_file(&temp_path);
+            return corrupt;
+        }
+
+        if let Err(err) = fs::rename(&temp_path, dst) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(anyhow!(err).context(format!("cannot replace destination file {}", dst.to_string_lossy())));
+        }
+
+        return corrupt;
+    }
+
+    fn read_to_temp_file(&self, contents: &[HashResult], dst: &Path) -> anyhow::Result<Vec<anyhow::Error>> {
+        let dst_file = File::create_new(dst)
+            .with_context(|| format!("cannot create destination file {}", dst.to_string_lossy()))?;
+        let mut writer = BufWriter::new(dst_file);
+        let mut buffer = vec![0u8; misc_helper::BUFFER_SIZE];
+        let mut corrupt = Vec::new();
+
+        for content in contents {
+            let mut reader = match self.store.open_content(content, Some(self.content_hasher(content.algo()))) {
+                Ok(reader) => reader,
+                Err(err) => {
+                    corrupt.push(err);
+                    continue;
+                }
+            };
+
+            loop {
+                let read_size = match reader.read(&mut buffer) {
+                    Ok(read_size) => read_size,
+                    Err(err) => {
+                        corrupt.push(anyhow!(err).context(format!("cannot read content {}", content.to_hex())));
+                        break;
+                    }
+                };
+                if read_size == 0 {
+                    let calculated = reader.hash().expect("content reader without digest");
+                    if calculated.data() != content.data() {
+                        corrupt.push(anyhow!("content {} is corrupt; calculated {}", content.to_hex(), calculated.to_string()));
+                    }
+                    break;
+                }
+
+                writer.write_all(&buffer[0..read_size])
+                    .with_context(|| format!("cannot write destination file {}", dst.to_string_lossy()))?;
+            }
+        }
+        writer.flush()
+            .with_context(|| format!("cannot write destination file {}", dst.to_string_lossy()))?;
+
+        return Ok(corrupt);
+    }
+
+    /// Whether the same data gets the same content id in both archives.
+    pub fn same_content_ids(&self, other: &BackupSession) -> bool {
+        return self.settings.hash_algo == other.settings.hash_algo && self.secret == other.secret;
+    }
+
+    /// Open a revision file; decrypts it in encrypted archives.
+    pub fn open_revision(&self, key: &str) -> anyhow::Result<Box<dyn Read>> {
+        let reader = self.backend.get(key)
+            .with_context(|| format!("cannot open revision {}", key))?;
+        return crypto::decrypt_reader(reader, self.get_key());
+    }
+
+    /// Create a revision file; encrypts it in encrypted archives. It only appears once committed.
+    pub fn create_revision(&self, key: &str) -> anyhow::Result<Box<dyn ObjectWriter>> {
+        let writer = self.backend.put(key)
+            .with_context(|| format!("cannot create revision {}", key))?;
+        return Ok(match self.get_key() {
+            Some(content_key) => Box::new(EncryptWriter::new(writer, content_key)?),
+            None => writer,
+        });
+    }
+
     pub fn channel_names(&self) -> anyhow::Result<Vec<String>> {
-        let mut ret: Vec<String> = Vec::new();
-
-        for path in channel_paths(&self.archive_dir)? {
-            ret.push(
-                path.iter()
-                    .last()
-                    .ok_or(anyhow!("cannot unpack channel name"))?
-                    .to_string_lossy()
-                    .to_string(),
-            );
+        return channel_names(self.backend.as_ref());
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockMode {
+    Exclusive,
+    Shared,
+}
+
+/// Who holds a lock; stored in the lock file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LockInfo {
+    pub pid: u32,
+    pub host: String,
+    /// seconds since the unix epoch
+    pub since: i64,
+}
+
+impl LockInfo {
+    fn current() -> LockInfo {
+        return LockInfo {
+            pid: std::process::id(),
+            host: misc_helper::hostname(),
+            since: Utc::now().timestamp(),
+        };
+    }
+
+    /// Only processes of this host can be checked; locks of other hosts are never stale.
+    pub fn is_stale(&self) -> bool {
+        return self.is_local() && !misc_helper::process_alive(self.pid);
+    }
+
+    /// Whether the process is known to run; only processes of this host can be checked.
+    pub fn is_alive(&self) -> bool {
+        return self.is_local() && misc_helper::process_alive(self.pid);
+    }
+
+    fn is_local(&self) -> bool {
+        return !self.host.is_empty() && self.host == misc_helper::hostname();
+    }
+
+    pub fn describe(&self) -> String {
+        let since = DateTime::<Utc>::from_timestamp(self.since, 0).unwrap_or_default();
+        return format!("process {} on {} since {}", self.pid, self.host, since.format("%Y-%m-%d %H:%M:%S UTC"));
+    }
+}
+
+/// Lock files written before they had content have no info.
+fn read_lock_info(backend: &dyn Backend, key: &str) -> Option<LockInfo> {
+    let data = backend.get_data(key).ok()?;
+    return serde_json::from_slice(&data).ok();
+}
+
+/// Remove the lock `key` when its process is gone; returns false when it is held.
+///
+/// Two processes may find the same stale lock. So it is moved away first, and
+/// only removed when what was moved is still the stale lock; otherwise it is the
+/// fresh lock of another process, which is put back.
+fn remove_stale_lock(backend: &dyn Backend, key: &str) -> anyhow::Result<bool> {
+    let stale_data = backend.get_data(key).ok();
+    let Some(info) = stale_data.as_ref().and_then(|x| serde_json::from_slice::<LockInfo>(x).ok()) else {
+        //removed in between
+        return Ok(!backend.exists(key)?);
+    };
+
+    if !info.is_stale() {
+        return Ok(false);
+    }
+
+    let moved = temp_key(key);
+    if backend.rename(key, &moved).is_err() {
+        //another process moved it first
+        return Ok(!backend.exists(key)?);
+    }
+
+    let moved_data = backend.get_data(&moved)?;
+    if Some(&moved_data) != stale_data.as_ref() {
+        backend.lock(key, &moved_data)
+            .with_context(|| format!("cannot put back lock {} which was taken in between", key))?;
+        backend.delete(&moved)?;
+        return Ok(false);
+    }
+
+    println!("remove stale lock of {}", info.describe());
+    backend.delete(&moved)?;
+
+    return Ok(true);
+}
+
+fn locked_error(backend: &dyn Backend, key: &str) -> anyhow::Error {
+    let user = match key == LOCK_FILE {
+        true => "locked",
+        false => "read",
+    };
+
+    return match read_lock_info(backend, key) {
+        Some(info) => anyhow!(
+            "archive {} is {} by {}; use break-lock when that process is gone",
+            backend.location(), user, info.describe()),
+        None => anyhow!(
+            "archive {} is {} by another process; use break-lock when no process uses it",
+            backend.location(), user),
+    };
+}
+
+#[derive(Default)]
+pub struct BreakLockReport {
+    pub removed: Vec<(String, Option<LockInfo>)>,
+    /// locks of processes which still run on this host
+    pub kept: Vec<(String, LockInfo)>,
+}
+
+/// Remove the locks of the archive, e.g. of a crashed process on another host.
+///
+/// Locks of processes which still run on this host are kept unless `force` is given.
+pub fn break_lock(archive: &str, force: bool) -> anyhow::Result<BreakLockReport> {
+    let backend = open_backend(archive)?;
+    let mut report = BreakLockReport::default();
+
+    let mut keys = backend.list(LOCKS_DIR)?;
+    if backend.exists(LOCK_FILE)? {
+        keys.push(LOCK_FILE.to_owned());
+    }
+
+    for key in keys {
+        let info = read_lock_info(backend.as_ref(), &key);
+        if let Some(info) = info.as_ref().filter(|x| !force && x.is_alive()) {
+            report.kept.push((key, info.clone()));
+            continue;
         }
 
-        return Ok(ret);
+        backend.delete(&key)
+            .with_context(|| format!("cannot remove lock {}", key))?;
+        report.removed.push((key, info));
     }
+
+    return Ok(report);
 }
 
+/// Lock of the archive; released when dropped.
+///
+/// A writer holds the exclusive lock file, each reader a file of its own in the
+/// locks dir. Both first create their lock and then look for the other kind,
+/// so of two racing processes at least one sees the other and backs off.
 struct ArchiveLock {
-    archive_dir: Option<PathBuf>,
+    backend: Option<Arc<dyn Backend>>,
+    key: String,
 }
 
 impl ArchiveLock {
-    pub fn new(archive_dir: PathBuf) -> anyhow::Result<ArchiveLock> {
-        fs::File::create_new(lock_file(&archive_dir))
-            .with_context(|| "backup storage is locked")?;
+    pub fn new(backend: Arc<dyn Backend>, mode: LockMode) -> anyhow::Result<ArchiveLock> {
+        let info = LockInfo::current();
+        let content = serde_json::to_vec_pretty(&info)?;
 
-        return Ok(ArchiveLock {
-            archive_dir: Some(archive_dir),
-        });
+        let key = match mode {
+            LockMode::Exclusive => LOCK_FILE.to_owned(),
+            LockMode::Shared => shared_lock_file(&format!("{}-{:016x}", info.pid, rand::random::<u64>())),
+        };
+
+        if let Err(err) = backend.lock(&key, &content) {
+            if !backend.exists(&key)? {
+                return Err(err).with_context(|| format!("cannot lock archive {}", backend.location()));
+            }
+            if !remove_stale_lock(backend.as_ref(), &key)? {
+                return Err(locked_error(backend.as_ref(), &key));
+            }
+            backend.lock(&key, &content)
+                .with_context(|| format!("cannot lock archive {}", backend.location()))?;
+        }
+
+        //from here on the lock is released on errors
+        let lock = ArchiveLock {
+            backend: Some(backend.clone()),
+            key: key,
+        };
+
+        let others = match mode {
+            LockMode::Exclusive => backend.list(LOCKS_DIR)?,
+            LockMode::Shared => vec![LOCK_FILE.to_owned()],
+        };
+
+        for other in others {
+            if backend.exists(&other)? && !remove_stale_lock(backend.as_ref(), &other)? {
+                return Err(locked_error(backend.as_ref(), &other));
+            }
+        }
+
+        return Ok(lock);
     }
 
     pub fn unlock(&mut self) {
-        let Some(archive_dir) = &self.archive_dir else {
+        let Some(backend) = &self.backend else {
             return;
         };
 
-        if let Ok(_) = fs::remove_file(lock_file(&archive_dir)) {
-            self.archive_dir = None;
+        if let Ok(_) = backend.delete(&self.key) {
+            self.backend = None;
         } else {
-            println!("cannot unlock {}", archive_dir.to_string_lossy());
+            println!("cannot unlock {}", backend.location());
         }
     }
 }
@@ -119,4 +491,4 @@ pub trait ToSession {
 
 pub trait GetSession<'a> {
     fn get_session(&'a self) -> &'a BackupSession;
-}
\ No newline at end of file
+}
@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context};
+use crate::dirwalk::{DirWalk, DirWalkParameters};
+use crate::misc_helper;
+use super::channel_reader::{ChannelItemKind, ChannelReader, ChannelReaderItem};
+use super::file_meta::{FileSignature, SpecialKind};
+use super::session::{BackupSession, ToSession};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatusChange {
+    New,
+    /// the content, the target or the kind of the item changed
+    Modified,
+    Deleted,
+}
+
+impl StatusChange {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            StatusChange::New => "new",
+            StatusChange::Modified => "modified",
+            StatusChange::Deleted => "deleted",
+        };
+    }
+}
+
+pub struct StatusEntry {
+    pub change: StatusChange,
+    pub path: PathBuf,
+}
+
+#[derive(Default)]
+pub struct StatusReport {
+    /// new and modified items in the order of the source dir, then the deleted ones
+    pub entries: Vec<StatusEntry>,
+    pub new: usize,
+    pub modified: usize,
+    pub deleted: usize,
+}
+
+impl StatusReport {
+    fn add(&mut self, change: StatusChange, path: PathBuf) {
+        match change {
+            StatusChange::New => self.new += 1,
+            StatusChange::Modified => self.modified += 1,
+            StatusChange::Deleted => self.deleted += 1,
+        }
+        self.entries.push(StatusEntry { change, path });
+    }
+}
+
+pub struct StatusOptions {
+    pub follow_symlinks: bool,
+    /// hash every file, even when size, mtime and inode are unchanged
+    pub rehash: bool,
+}
+
+/// Whether the file `path` has the content of the backed up file `item`.
+fn same_file(session: &BackupSession, path: &Path, metadata: &fs::Metadata, item: &ChannelReaderItem, rehash: bool) -> anyhow::Result<bool> {
+    let ChannelItemKind::File { checksum, signature, .. } = &item.kind else {
+        return Ok(false);
+    };
+
+    if let Some(signature) = signature {
+        if metadata.len() != signature.size {
+            return Ok(false);
+        }
+        if !rehash && FileSignature::from_metadata(metadata) == *signature {
+            return Ok(true);
+        }
+    }
+
+    let file = fs::File::open(path)
+        .with_context(|| format!("cannot open {}", path.to_string_lossy()))?;
+    let calculated = session.content_hasher(checksum.algo()).stream(file)
+        .with_context(|| format!("cannot read {}", path.to_string_lossy()))?;
+    return Ok(calculated.data() == checksum.data());
+}
+
+/// Compare `src_dir` with the revision of `channel_reader` and collect new,
+/// modified and deleted items. Metadata is not compared.
+pub fn source_status(channel_reader: ChannelReader, src_dir: &Path, options: &StatusOptions) -> anyhow::Result<(BackupSession, StatusReport)> {
+    let mut channel_reader = channel_reader;
+    let mut items = BTreeMap::new();
+    for item in &mut channel_reader {
+        let item = item?;
+        items.insert(item.relative_path.clone(), item);
+    }
+    let session = channel_reader.to_session();
+
+    let dirwalk = DirWalk::new(DirWalkParameters {
+        root_dir: src_dir.to_owned(),
+        recursive: true,
+        follow_symlinks: options.follow_symlinks,
+        filter: None,
+    })
+    .ok_or(anyhow!("cannot read source dir {}", src_dir.to_string_lossy()))?;
+
+    let mut report = StatusReport::default();
+    let mut seen = HashSet::new();
+
+    for file_path in dirwalk {
+        let relative_path = misc_helper::relative_path(src_dir, &file_path);
+        let metadata = match options.follow_symlinks {
+            true => file_path.metadata().or_else(|_| file_path.symlink_metadata()),
+            false => file_path.symlink_metadata(),
+        }
+        .with_context(|| format!("cannot get metadata of {}", file_path.to_string_lossy()))?;
+
+        let Some(item) = items.get(&relative_path) else {
+            report.add(StatusChange::New, relative_path);
+            continue;
+        };
+        seen.insert(relative_path.clone());
+
+        //which of the links to a file is stored as hardlink depends on the backup order
+        let item = match &item.kind {
+            ChannelItemKind::Hardlink { target } => items.get(target).unwrap_or(item),
+            _ => item,
+        };
+
+        let same = match &item.kind {
+            ChannelItemKind::File { .. } => metadata.is_file() && same_file(&session, &file_path, &metadata, item, options.rehash)?,
+            ChannelItemKind::Dir => metadata.is_dir(),
+            ChannelItemKind::Symlink { target } => metadata.is_symlink() && fs::read_link(&file_path)? == *target,
+            ChannelItemKind::Hardlink { .. } => false,
+            ChannelItemKind::Special { kind, rdev } =>
+                SpecialKind::from_metadata(&metadata) == Some(*kind) && misc_helper::rdev(&metadata) == *rdev,
+        };
+
+        if !same {
+            report.add(StatusChange::Modified, relative_path);
+        }
+    }
+
+    for path in items.into_keys().filter(|x| !seen.contains(x)) {
+        report.add(StatusChange::Deleted, path);
+    }
+
+    return Ok((session, report));
+}
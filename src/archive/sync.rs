@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context};
+use crate::checksum::{HashResult, Hasher};
+use crate::{meta_format, misc_helper};
+use super::defs;
+use super::content::ContentWriter;
+use super::session::BackupSession;
+
+/// How two archives are synced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncMode {
+    /// copy missing revisions to the target and remove the ones the source does not have;
+    /// the sync command then runs gc on the target to remove content they alone referenced
+    Mirror,
+    /// copy missing revisions in both directions
+    Union,
+}
+
+impl FromStr for SyncMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<SyncMode> {
+        return match value {
+            "mirror" => Ok(SyncMode::Mirror),
+            "union" => Ok(SyncMode::Union),
+            _ => Err(anyhow!("unknown sync mode {}", value)),
+        };
+    }
+}
+
+pub struct SyncOptions {
+    /// only sync this channel; all channels otherwise
+    pub channel: Option<String>,
+    /// remove revisions of the target which the source does not have
+    pub remove_missing: bool,
+    /// hash the content again when the archives name content differently
+    pub reencode: bool,
+}
+
+#[derive(Default)]
+pub struct SyncReport {
+    /// keys of the revisions copied to the target
+    pub copied: Vec<String>,
+    /// keys of the revisions removed from the target
+    pub removed: Vec<String>,
+    pub content_copied: usize,
+    pub bytes_copied: u64,
+}
+
+/// Copies content between two archives.
+struct ContentCopier {
+    reencode: bool,
+    /// id in the source -> id in the target, of content and of whole files
+    ids: HashMap<HashResult, HashResult>,
+}
+
+impl ContentCopier {
+    /// Decode `content` from the source, check it against its id and store it in the target.
+    ///
+    /// The content is encoded for the target into a temporary file first, so
+    /// nothing is stored before its hash is verified. Returns the id in the target.
+    fn copy_content(
+        &mut self,
+        source: &BackupSession,
+        target: &mut BackupSession,
+        content: &HashResult,
+        mut file_hasher: Option<&mut dyn Hasher>,
+        report: &mut SyncReport,
+    ) -> anyhow::Result<HashResult> {
+        let mut reader = source.content_store().open_content(content, None)?;
+        let mut source_hasher = source.content_hasher(content.algo());
+        let mut target_hasher = match self.reencode {
+            true => Some(target.content_hasher(target.get_settings().hash_algo)),
+            false => None,
+        };
+
+        let mut file = misc_helper::temp_file()?;
+        let mut writer = ContentWriter::new(Box::new(file.try_clone()?), target.get_settings(), target.get_key())?;
+        let mut buffer = vec![0u8; misc_helper::BUFFER_SIZE];
+
+        loop {
+            let read_size = reader.read(&mut buffer)
+                .with_context(|| format!("cannot read content {}", content.to_hex()))?;
+            if read_size == 0 {
+                break;
+            }
+
+            let data = &buffer[0..read_size];
+            source_hasher.update(data);
+            if let Some(target_hasher) = &mut target_hasher {
+                target_hasher.update(data);
+            }
+            if let Some(file_hasher) = &mut file_hasher {
+                file_hasher.update(data);
+            }
+            writer.write_all(data)?;
+        }
+        let size = writer.finish()?;
+
+        let calculated = source_hasher.finalize();
+        if calculated.data() != content.data() {
+            bail!("content {} is corrupt; calculated {}", content.to_hex(), calculated.to_string());
+        }
+
+        let target_id = match &mut target_hasher {
+            Some(target_hasher) => target_hasher.finalize(),
+            None => content.clone(),
+        };
+
+        file.seek(SeekFrom::Start(0))?;
+        if target.content_store_mut().add_encoded(&target_id, size, &mut file)? {
+            report.content_copied += 1;
+            report.bytes_copied += size;
+        }
+
+        return Ok(target_id);
+    }
+
+    /// Copy the content of a file which the target misses; returns the hash and
+    /// the chunks of the file in the target.
+    fn copy_file(
+        &mut self,
+        source: &BackupSession,
+        target: &mut BackupSession,
+        checksum: &HashResult,
+        chunks: &[HashResult],
+        report: &mut SyncReport,
+    ) -> anyhow::Result<(HashResult, Vec<HashResult>)> {
+        let contents = match chunks.is_empty() {
+            true => vec![checksum.clone()],
+            false => chunks.to_vec(),
+        };
+
+        if !self.reencode {
+            for content in &contents {
+                if !target.content_store().contains(content)? {
+                    self.copy_content(source, target, content, None, report)?;
+                }
+            }
+            return Ok((checksum.clone(), chunks.to_vec()));
+        }
+
+        //files are in many revisions; each is only read once
+        let known_chunks: Option<Vec<HashResult>> = chunks.iter().map(|x| self.ids.get(x).cloned()).collect();
+        if let (Some(known_checksum), Some(known_chunks)) = (self.ids.get(checksum), known_chunks) {
+            return Ok((known_checksum.clone(), known_chunks));
+        }
+
+        //the hash of a chunked file covers all of its chunks
+        let mut file_hasher = target.content_hasher(target.get_settings().hash_algo);
+        let mut target_contents = Vec::new();
+        for content in &contents {
+            target_contents.push(self.copy_content(source, target, content, Some(file_hasher.as_mut()), report)?);
+        }
+
+        let (target_checksum, target_chunks) = match chunks.is_empty() {
+            true => (target_contents[0].clone(), Vec::new()),
+            false => (file_hasher.finalize(), target_contents),
+        };
+
+        self.ids.insert(checksum.clone(), target_checksum.clone());
+        for (chunk, target_chunk) in chunks.iter().zip(&target_chunks) {
+            self.ids.insert(chunk.clone(), target_chunk.clone());
+        }
+
+        return Ok((target_checksum, target_chunks));
+    }
+
+    /// Copy a revision with its content; the revision is written last, so it
+    /// never references content the target does not have.
+    fn copy_revision(
+        &mut self,
+        source: &BackupSession,
+        target: &mut BackupSession,
+        rev_path: &str,
+        report: &mut SyncReport,
+    ) -> anyhow::Result<()> {
+        meta_format::verify(source.open_revision(rev_path)?)
+            .with_context(|| format!("revision {} is corrupt", rev_path))?;
+
+        let mut entries: Vec<meta_format::ReaderEntry> = meta_format::Reader::new(source.open_revision(rev_path)?)
+            .filter(|x| !meta_format::is_end_marker(&x.key))
+            .collect();
+
+        let mut item_start = 0;
+        while item_start < entries.len() {
+            let item_end = entries[item_start + 1..]
+                .iter()
+                .position(|x| defs::keys::is_item(&x.key))
+                .map_or(entries.len(), |x| item_start + 1 + x);
+            let item = &mut entries[item_start..item_end];
+            item_start = item_end;
+
+            if item[0].key != defs::keys::FILE {
+                continue;
+            }
+
+            let mut checksum = None;
+            let mut chunks = Vec::new();
+            for entry in item.iter() {
+                if entry.key == defs::keys::HASH {
+                    checksum = Some(HashResult::parse(&entry.value)?);
+                } else if entry.key == defs::keys::CHUNK {
+                    chunks.push(HashResult::parse(&entry.value)?);
+                }
+            }
+            let checksum = checksum.ok_or(anyhow!("checksum of {} is missing", item[0].value))?;
+
+            let (target_checksum, target_chunks) = self.copy_file(source, target, &checksum, &chunks, report)
+                .with_context(|| format!("cannot copy {} of revision {}", item[0].value, rev_path))?;
+
+            let mut target_chunks = target_chunks.iter();
+            for entry in item.iter_mut() {
+                if entry.key == defs::keys::HASH {
+                    entry.value = target_checksum.to_string();
+                } else if entry.key == defs::keys::CHUNK {
+                    entry.value = target_chunks.next().ok_or(anyhow!("chunk count changed"))?.to_string();
+                }
+            }
+        }
+
+        //the pack must be indexed before the revision referencing it is finished
+        target.content_store_mut().flush()?;
+
+        let mut writer = meta_format::Writer::new(target.create_revision(rev_path)?);
+        let mut depth = 0;
+        for entry in &entries {
+            while depth < entry.depth {
+                writer.increase_depth();
+                depth += 1;
+            }
+            while depth > entry.depth {
+                writer.decrease_depth();
+                depth -= 1;
+            }
+            writer.add_entry(&entry.key, &entry.value)?;
+        }
+        writer.finish()?.commit()
+            .with_context(|| format!("cannot write revision {}", rev_path))?;
+
+        return Ok(());
+    }
+}
+
+fn rev_names(session: &BackupSession, channel: &str) -> anyhow::Result<Vec<String>> {
+    let mut ret: Vec<String> = defs::channel_rev_paths(session.backend().as_ref(), channel)?
+        .iter()
+        .map(|x| defs::key_name(x).to_owned())
+        .collect();
+    ret.sort();
+    return Ok(ret);
+}
+
+/// Copy the revisions of `source` which `target` misses, with the content they reference.
+///
+/// Revisions keep their names, so a revision is in both archives when the names
+/// match. Content ids only match when both archives hash the same way; otherwise
+/// the content is only copied with `reencode`, which rehashes it for the target.
+pub fn sync_archive(source: &BackupSession, target: &mut BackupSession, options: &SyncOptions) -> anyhow::Result<SyncReport> {
+    let reencode = !source.same_content_ids(target);
+    if reencode && !options.reencode {
+        bail!(
+            "{} and {} hash content differently ({} and {}, or another secret); the content must be reencoded",
+            source.backend().location(),
+            target.backend().location(),
+            source.get_settings().hash_algo.as_str(),
+            target.get_settings().hash_algo.as_str()
+        );
+    }
+
+    let channels = match &options.channel {
+        Some(channel) => vec![channel.clone()],
+        None => {
+            let mut channels = source.channel_names()?;
+            if options.remove_missing {
+                channels.extend(target.channel_names()?);
+            }
+            channels.sort();
+            channels.dedup();
+            channels
+        }
+    };
+
+    let mut report = SyncReport::default();
+    let mut copier = ContentCopier {
+        reencode: reencode,
+        ids: HashMap::new(),
+    };
+
+    for channel in channels {
+        let source_revs = rev_names(source, &channel)?;
+        let target_revs = rev_names(target, &channel)?;
+
+        for rev in &source_revs {
+            if target_revs.contains(rev) {
+                continue;
+            }
+
+            let rev_path = defs::channel_file(&channel, rev);
+            copier.copy_revision(source, target, &rev_path, &mut report)?;
+            report.copied.push(rev_path);
+        }
+
+        if !options.remove_missing {
+            continue;
+        }
+
+        for rev in &target_revs {
+            if source_revs.contains(rev) {
+                continue;
+            }
+
+            let rev_path = defs::channel_file(&channel, rev);
+            target.backend().delete(&rev_path)?;
+            report.removed.push(rev_path);
+        }
+    }
+
+    return Ok(report);
+}
@@ -1,2 +1,178 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context};
+use crate::checksum::{self, HashResult};
+use crate::{meta_format, misc_helper};
+use super::defs;
+use super::content_store::{ContentLocation, StoredContent};
+use super::channel_reader::{ChannelItemKind, ChannelReader, ChannelReaderOptions};
+use super::session::{BackupSession, GetSession, ToSession};
 
+pub enum VerifyProblem {
+    RevisionCorrupt {
+        rev_path: String,
+        err: anyhow::Error,
+    },
+    RevisionUnreadable {
+        rev_path: String,
+        err: anyhow::Error,
+    },
+    ContentMissing {
+        rev_path: String,
+        relative_path: PathBuf,
+        content_id: String,
+    },
+    ContentCorrupt {
+        content: StoredContent,
+        err: anyhow::Error,
+    },
+}
 
+impl VerifyProblem {
+    pub fn print(&self) {
+        match self {
+            VerifyProblem::RevisionCorrupt { rev_path, err } => {
+                eprintln!("revision corrupt    {}", rev_path);
+                misc_helper::print_error_chain(err);
+            }
+            VerifyProblem::RevisionUnreadable { rev_path, err } => {
+                eprintln!("revision unreadable {}", rev_path);
+                misc_helper::print_error_chain(err);
+            }
+            VerifyProblem::ContentMissing { rev_path, relative_path, content_id } => {
+                eprintln!(
+                    "content missing     {} -> {} (revision {})",
+                    relative_path.to_string_lossy(),
+                    content_id,
+                    rev_path
+                );
+            }
+            VerifyProblem::ContentCorrupt { content, err } => {
+                match &content.location {
+                    ContentLocation::Loose(key) => eprintln!("content corrupt     {}", key),
+                    ContentLocation::Packed(location) => eprintln!(
+                        "content corrupt     {} (pack {})", content.id, location.pack),
+                }
+                misc_helper::print_error_chain(err);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct VerifyReport {
+    pub revs_checked: usize,
+    /// revisions which are corrupt or cannot be read, at least partly
+    pub revs_failed: usize,
+    pub content_checked: usize,
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        return self.problems.is_empty();
+    }
+}
+
+pub fn verify_channel_rev(rev_path: &str, session: &BackupSession) -> anyhow::Result<()> {
+    return meta_format::verify(session.open_revision(rev_path)?);
+}
+
+/// Re-hash a stored blob and compare it with `expected`.
+///
+/// Without an expected hash, e.g. for unreferenced content, the id is
+/// taken as hash of the current algorithm of the archive.
+pub fn verify_content(content: &StoredContent, expected: Option<&HashResult>, session: &BackupSession) -> anyhow::Result<()> {
+    let expected = match expected {
+        Some(expected) => expected.clone(),
+        None => HashResult::from_hex_string(session.get_settings().hash_algo, &content.id)
+            .with_context(|| "content name is not a hash")?,
+    };
+
+    let reader = session.content_store().open_stored(content, None)?;
+    let calculated = session.content_hasher(expected.algo()).stream(reader)?;
+
+    if calculated.data() != expected.data() {
+        bail!("hashsum mismatch; calculated {}", calculated.to_string());
+    }
+
+    return Ok(());
+}
+
+/// Check every revision of every channel and every content blob of the archive.
+///
+/// Problems are collected in the report instead of aborting on the first one.
+pub fn verify_all(session: BackupSession) -> anyhow::Result<(BackupSession, VerifyReport)> {
+    let mut report = VerifyReport::default();
+    let mut session = session;
+    let mut expected_hashes: HashMap<String, HashResult> = HashMap::new();
+
+    for channel in session.channel_names()? {
+        for rev_path in defs::channel_rev_paths(session.backend().as_ref(), &channel)? {
+            report.revs_checked += 1;
+
+            if let Err(err) = verify_channel_rev(&rev_path, &session) {
+                report.revs_failed += 1;
+                report.problems.push(VerifyProblem::RevisionCorrupt { rev_path, err });
+                continue;
+            }
+
+            let file = match session.open_revision(&rev_path) {
+                Ok(file) => file,
+                Err(err) => {
+                    report.revs_failed += 1;
+                    report.problems.push(VerifyProblem::RevisionUnreadable { rev_path, err });
+                    continue;
+                }
+            };
+
+            let mut channel_reader = ChannelReader::from_revision(session, &channel, file);
+            let mut rev_failed = false;
+
+            while let Some(item) = channel_reader.next() {
+                let item = match item {
+                    Ok(item) => item,
+                    Err(err) => {
+                        rev_failed = true;
+                        report.problems.push(VerifyProblem::RevisionUnreadable { rev_path: rev_path.clone(), err });
+                        continue;
+                    }
+                };
+
+                let ChannelItemKind::File { contents, .. } = item.kind else {
+                    continue;
+                };
+
+                for content in contents {
+                    if !channel_reader.get_session().content_store().contains(&content)? {
+                        report.problems.push(VerifyProblem::ContentMissing {
+                            rev_path: rev_path.clone(),
+                            content_id: content.to_hex(),
+                            relative_path: item.relative_path.clone(),
+                        });
+                        continue;
+                    }
+
+                    expected_hashes.insert(content.to_hex(), content);
+                }
+            }
+
+            if rev_failed {
+                report.revs_failed += 1;
+            }
+
+            session = channel_reader.to_session();
+        }
+    }
+
+    for content in session.content_store().list()? {
+        report.content_checked += 1;
+
+        if let Err(err) = verify_content(&content, expected_hashes.get(&content.id), &session) {
+            report.problems.push(VerifyProblem::ContentCorrupt { content, err });
+        }
+    }
+
+    return Ok((session, report));
+}
@@ -1,10 +1,12 @@
 use std::{fs::File, hash::Hash, io::{BufReader, Read}, path::Path};
 use bzip2::read;
 use hex;
-use sha2::{self, Digest, Sha256};
+use sha2::{self, Digest, Sha256, Sha512_256};
 use digest::{self, generic_array::ArrayLength, FixedOutputReset, OutputSizeUser};
+use hmac::{Hmac, Mac};
 use anyhow::{anyhow, Context};
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
 pub const OUTPUT_SIZE_SHORT: usize = 4;
 
@@ -18,30 +20,77 @@ fn outer<>() {
     doit::<MyType, Sha256>();
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum HashAlgo {
     Sha256,
+    Sha512_256,
+    Blake3,
 }
 
+impl HashAlgo {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512_256 => "sha512-256",
+            HashAlgo::Blake3 => "blake3",
+        };
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<HashAlgo> {
+        return match value {
+            "sha256" => Ok(HashAlgo::Sha256),
+            "sha512-256" => Ok(HashAlgo::Sha512_256),
+            "blake3" => Ok(HashAlgo::Blake3),
+            _ => Err(anyhow!("unknown hash algorithm {}", value)),
+        };
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct HashResult{
+    algo: HashAlgo,
     digest: Vec<u8>,
-    //TODO: maybe algo so we can check again
 }
 impl HashResult {
-    pub fn from_data(data: &[u8]) -> HashResult {
+    pub fn from_data(algo: HashAlgo, data: &[u8]) -> HashResult {
         return HashResult {
+            algo: algo,
             digest: data.into(),
         };
     }
 
-    pub fn from_hex_string(digest: &str) -> anyhow::Result<HashResult> {
+    pub fn from_hex_string(algo: HashAlgo, digest: &str) -> anyhow::Result<HashResult> {
         let decoded = hex::decode(digest)?;
         return Ok(HashResult {
+            algo: algo,
             digest: decoded
         });
     }
 
+    /// Parses `algo:hex` as written by [`HashResult::to_string`]; a plain hex
+    /// string is a sha256 of revisions written before the algorithm was recorded.
+    pub fn parse(value: &str) -> anyhow::Result<HashResult> {
+        return match value.split_once(':') {
+            Some((algo, digest)) => HashResult::from_hex_string(algo.parse()?, digest),
+            None => HashResult::from_hex_string(HashAlgo::Sha256, value),
+        }
+        .with_context(|| format!("invalid hash {}", value));
+    }
+
+    pub fn algo(&self) -> HashAlgo {
+        return self.algo;
+    }
+
+    /// `algo:hex`
     pub fn to_string(&self) -> String {
+        return format!("{}:{}", self.algo.as_str(), self.to_hex());
+    }
+
+    pub fn to_hex(&self) -> String {
         return hex::encode(&self.digest);
     }
     
@@ -64,14 +113,16 @@ pub trait Hasher {
 struct DigestImpl<T> where 
     T: Digest
 {
+    algo: HashAlgo,
     hasher: T,
 }
 
 impl<T> DigestImpl<T>  where 
     T: Digest 
 {
-    fn new() -> DigestImpl<T> {
+    fn new(algo: HashAlgo) -> DigestImpl<T> {
         return DigestImpl{
+            algo: algo,
             hasher: T::new()
         };
     }
@@ -86,14 +137,33 @@ impl<T> Hasher for DigestImpl<T>  where
 
     fn finalize(&mut self, ) -> HashResult {
         return HashResult{
+            algo: self.algo,
             digest: self.hasher.finalize_reset().to_vec()
         };
     }
 }
 
+struct Blake3Impl {
+    hasher: blake3::Hasher,
+}
+
+impl Hasher for Blake3Impl {
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn finalize(&mut self, ) -> HashResult {
+        let digest = self.hasher.finalize();
+        self.hasher.reset();
+        return HashResult::from_data(HashAlgo::Blake3, digest.as_bytes());
+    }
+}
+
 pub fn new_hasher(algo: HashAlgo) -> Box<dyn Hasher> {
     match algo {
-        HashAlgo::Sha256 => { return Box::new(DigestImpl::<Sha256>::new()) as Box<dyn Hasher>; }
+        HashAlgo::Sha256 => { return Box::new(DigestImpl::<Sha256>::new(algo)) as Box<dyn Hasher>; }
+        HashAlgo::Sha512_256 => { return Box::new(DigestImpl::<Sha512_256>::new(algo)) as Box<dyn Hasher>; }
+        HashAlgo::Blake3 => { return Box::new(Blake3Impl{ hasher: blake3::Hasher::new() }) as Box<dyn Hasher>; }
     }
 }
 
@@ -117,11 +187,60 @@ impl dyn Hasher {
     }   
 }
 
-fn new_hasher_salt(algo: HashAlgo, salt: &[u8]) -> Box<dyn Hasher> {
-    let mut hasher = new_hasher(algo);
-    hasher.update(salt);
-    return hasher;
+/// HMAC of the data; `initial` is the keyed state a new HMAC starts from after finalize.
+struct HmacImpl<T> where
+    T: Mac + Clone
+{
+    algo: HashAlgo,
+    initial: T,
+    mac: T,
 }
 
+impl<T> HmacImpl<T> where
+    T: Mac + Clone
+{
+    fn new(algo: HashAlgo, mac: T) -> HmacImpl<T> {
+        return HmacImpl{
+            algo: algo,
+            initial: mac.clone(),
+            mac: mac,
+        };
+    }
+}
 
+impl<T> Hasher for HmacImpl<T> where
+    T: Mac + Clone
+{
+    fn update(&mut self, data: &[u8]) {
+        Mac::update(&mut self.mac, data);
+    }
 
+    fn finalize(&mut self, ) -> HashResult {
+        let mac = std::mem::replace(&mut self.mac, self.initial.clone());
+        return HashResult{
+            algo: self.algo,
+            digest: mac.finalize().into_bytes().to_vec()
+        };
+    }
+}
+
+/// Context of the BLAKE3 key derived from the secret of an archive.
+const BLAKE3_KEY_CONTEXT: &str = "backuptool content names v1";
+
+/// Hasher keyed with `key`: HMAC for the SHA-2 algorithms, the keyed mode of BLAKE3.
+pub fn new_hasher_keyed(algo: HashAlgo, key: &[u8]) -> Box<dyn Hasher> {
+    match algo {
+        HashAlgo::Sha256 => {
+            let mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any size");
+            return Box::new(HmacImpl::new(algo, mac)) as Box<dyn Hasher>;
+        }
+        HashAlgo::Sha512_256 => {
+            let mac = Hmac::<Sha512_256>::new_from_slice(key).expect("hmac takes keys of any size");
+            return Box::new(HmacImpl::new(algo, mac)) as Box<dyn Hasher>;
+        }
+        HashAlgo::Blake3 => {
+            let key = blake3::derive_key(BLAKE3_KEY_CONTEXT, key);
+            return Box::new(Blake3Impl{ hasher: blake3::Hasher::new_keyed(&key) }) as Box<dyn Hasher>;
+        }
+    }
+}
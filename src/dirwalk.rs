@@ -1,28 +1,37 @@
-use std::fs::{read_dir, Metadata, ReadDir};
+use std::fs::{canonicalize, metadata, read_dir, Metadata, ReadDir};
 use std::path::{Path, PathBuf};
 use anyhow::{anyhow, bail, Context};
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 
 pub struct DirWalkParameters {
     pub root_dir: PathBuf,
     pub recursive: bool,
+    pub follow_symlinks: bool,
     pub filter: Option<fn(&Path) -> bool>,
 }
 
 pub struct DirWalk {
     remain: Vec<EntryList>,
     parameters: DirWalkParameters,
+    //canonical dirs already entered; only used with follow_symlinks to break cycles
+    visited: HashSet<PathBuf>,
 }
 
 impl DirWalk {
     pub fn new(parameters: DirWalkParameters) -> Option<DirWalk> {
-        let Ok(entries) = from_dir_entry(&parameters.root_dir) else {
+        let Ok(entries) = from_dir_entry(&parameters.root_dir, parameters.follow_symlinks) else {
             return None;
         };
 
+        let mut visited = HashSet::new();
+        if let Ok(root_dir) = canonicalize(&parameters.root_dir) {
+            visited.insert(root_dir);
+        }
+
         return Some(DirWalk {
             remain: vec![entries],
             parameters: parameters,
+            visited: visited,
         });
     }
 
@@ -30,11 +39,22 @@ impl DirWalk {
         return DirWalk::new(DirWalkParameters {
             root_dir: root_dir.to_owned(),
             recursive: true,
+            follow_symlinks: false,
             filter: None,
         });
     }
 }
 
+impl DirWalk {
+    fn is_new_dir(&mut self, path: &Path) -> bool {
+        if !self.parameters.follow_symlinks {
+            return true;
+        }
+
+        return canonicalize(path).is_ok_and(|x| self.visited.insert(x));
+    }
+}
+
 //TODO: currently we use PathBuf which is slower but easier to handle
 impl Iterator for DirWalk {
     type Item = PathBuf;
@@ -50,8 +70,8 @@ impl Iterator for DirWalk {
 
             let is_dir = entry.metadata.is_dir();
 
-            if is_dir && self.parameters.recursive {
-                let next_entry = from_dir_entry(&entry.path);
+            if is_dir && self.parameters.recursive && self.is_new_dir(&entry.path) {
+                let next_entry = from_dir_entry(&entry.path, self.parameters.follow_symlinks);
                 if let Ok(next_entry) = next_entry {
                     self.remain.push(next_entry);
                 }
@@ -77,7 +97,7 @@ struct Entry {
 
 type EntryList = VecDeque<Entry>;
 
-fn from_dir_entry(path: &Path) -> anyhow::Result<EntryList> {
+fn from_dir_entry(path: &Path, follow_symlinks: bool) -> anyhow::Result<EntryList> {
     let Ok(entries) = read_dir(&path) else {
         return Err(anyhow!("cannot read dir {}", path.to_string_lossy()));
     };
@@ -86,7 +106,11 @@ fn from_dir_entry(path: &Path) -> anyhow::Result<EntryList> {
 
     for entry in entries {
         let entry = entry.expect("cannot access the result of a read_dir");
-        let metadata = entry.metadata().expect("read metadata failed");
+        let metadata = match follow_symlinks {
+            true => metadata(entry.path()).or_else(|_| entry.metadata()),
+            false => entry.metadata(),
+        };
+        let metadata = metadata.expect("read metadata failed");
         ret.push_back(Entry{path: entry.path(), metadata});
     }
 
@@ -7,17 +7,22 @@ mod checksum;
 mod dirwalk;
 mod meta_format;
 mod misc_helper;
+mod path_filter;
 mod test;
 
 
-use archive::{BackupSession, ChannelReader, ChannelReaderOptions, ChannelWriter, ChannelWriterAdd,ContentCompression, GetSession};
+use archive::{BackupSession, ChannelReader, ChannelReaderOptions, ChannelWriter, ChannelItemKind, ChannelWriterAdd, ContentAddressing, ContentChunking, ContentCompression, ContentEncryption, ContentLayout, ContentLocation, ContentPacking, FileMeta, FileSignature, HashCache, GetSession, SpecialKind, StatusOptions, SyncMode};
 use checksum::HashAlgo;
 use clap::{Parser, Subcommand};
 use crossbeam;
+use filetime::FileTime;
 use dirwalk::{DirWalk, DirWalkParameters};
-use misc_helper::CopyAction;
+use path_filter::PathFilter;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
 use std::ops::DerefMut;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use anyhow::{anyhow, bail, Context};
@@ -25,10 +30,14 @@ use anyhow::{anyhow, bail, Context};
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
-    /// Path of the backup archive
+    /// Path of the backup archive, or a url: file://, sftp://[user@]host[:port]/path, s3://host/bucket/prefix, s3+http://...
     #[arg(short, long)]
     archive: String,
 
+    /// file with the password of an encrypted archive; otherwise BACKUPTOOL_PASSWORD is used
+    #[arg(long, global = true)]
+    password_file: Option<PathBuf>,
+
     /// write source files to the archive
     #[command(subcommand)]
     subcommands: Option<SubCli>,
@@ -37,7 +46,36 @@ struct Cli {
 #[derive(Subcommand)]
 enum SubCli {
     /// create a new arhive
-    New,
+    New {
+        /// compression of the content: none, lz4, bzip2[:1-9], zstd[:1-22] or xz[:0-9]
+        #[arg(long, default_value = "bzip2:9")]
+        compression: ContentCompression,
+
+        /// encrypt content and revisions with a key protected by a password
+        #[arg(long)]
+        encrypt: bool,
+
+        /// name content by a hash keyed with a secret of the archive, so stored files cannot be guessed;
+        /// the secret is encrypted, so it needs --encrypt
+        #[arg(long, requires = "encrypt")]
+        private: bool,
+
+        /// hash of new content: sha256, sha512-256 or blake3
+        #[arg(long, default_value = "sha256")]
+        hash: HashAlgo,
+
+        /// split large files into chunks for deduplication within files: none or fastcdc[:avg_size]
+        #[arg(long, default_value = "none")]
+        chunking: ContentChunking,
+
+        /// append small files to pack files instead of storing each on its own: none or packs[:max_blob_size]
+        #[arg(long, default_value = "none")]
+        packing: ContentPacking,
+
+        /// placement of content files: flat (content/<hash>) or sharded (content/ab/cd/<hash>)
+        #[arg(long, default_value = "flat")]
+        layout: ContentLayout,
+    },
 
     /// Write files from a source dir to archive
     Backup {
@@ -48,6 +86,14 @@ enum SubCli {
         /// channel name for the archive dir
         #[arg(short, long)]
         channel: String,
+
+        /// back up the files symlinks point to instead of the links
+        #[arg(long)]
+        follow_symlinks: bool,
+
+        /// hash every file, even when size, mtime and inode are unchanged since the last revision
+        #[arg(long)]
+        rehash: bool,
     },
 
     /// Restore a specific revision of a channel to a destination folder
@@ -63,11 +109,176 @@ enum SubCli {
         /// entry name
         #[arg(short, long)]
         entry: Option<String>,
+
+        /// what to do with a file whose content does not match its hash: fail, skip or keep
+        #[arg(long, default_value = "fail")]
+        on_corruption: OnCorruption,
+
+        /// what to do with items which exist already: overwrite, skip, newer, rename or fail;
+        /// it is applied after the content check, so an existing file with the backed up content is kept as unchanged
+        #[arg(long, default_value = "overwrite")]
+        on_conflict: OnConflict,
+
+        /// take existing files with the size and mtime of the backed up one as unchanged instead of hashing them
+        #[arg(long)]
+        trust_mtime: bool,
+
+        /// afterwards remove everything in the destination which was not restored; restores the whole revision,
+        /// so it cannot be combined with paths, globs or regexes
+        #[arg(long, conflicts_with_all = ["paths", "include", "exclude", "include_regex", "exclude_regex"])]
+        mirror: bool,
+
+        /// restore nothing; only list what --mirror would remove
+        #[arg(long, requires = "mirror")]
+        dry_run: bool,
+
+        /// only restore items matching one of these globs; without a / a glob matches any path component
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// do not restore items matching one of these globs
+        #[arg(long)]
+        exclude: Vec<String>,
+
+        /// only restore items whose path from the root of the revision matches one of these regexes
+        #[arg(long)]
+        include_regex: Vec<String>,
+
+        /// do not restore items whose path from the root of the revision matches one of these regexes
+        #[arg(long)]
+        exclude_regex: Vec<String>,
+
+        /// remove this many leading path components from every restored item
+        #[arg(long, default_value_t = 0)]
+        strip_components: usize,
+
+        /// only restore these paths of the revision and everything below them
+        paths: Vec<PathBuf>,
     },
 
     /// Verify the integrity of the archive
     Verify,
 
+    /// Remove content which is not referenced by any revision
+    Gc {
+        /// only list the content which would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Move the content files of the archive into another layout
+    MigrateLayout {
+        /// flat or sharded
+        #[arg(long)]
+        layout: ContentLayout,
+    },
+
+    /// Remove old revisions of a channel according to a retention policy
+    Prune {
+        /// channel name
+        #[arg(short, long)]
+        channel: String,
+
+        /// keep the n most recent revisions
+        #[arg(long, default_value_t = 0)]
+        keep_last: usize,
+
+        /// keep the most recent revision of each of the last n days
+        #[arg(long, default_value_t = 0)]
+        keep_daily: usize,
+
+        /// keep the most recent revision of each of the last n weeks
+        #[arg(long, default_value_t = 0)]
+        keep_weekly: usize,
+
+        /// keep the most recent revision of each of the last n months
+        #[arg(long, default_value_t = 0)]
+        keep_monthly: usize,
+
+        /// only list the revisions which would be removed
+        #[arg(long)]
+        dry_run: bool,
+
+        /// remove unreferenced content afterwards
+        #[arg(long)]
+        gc: bool,
+    },
+
+    /// Copy revisions with their content to another archive
+    Sync {
+        /// the other archive; a path or url like --archive
+        #[arg(long)]
+        to: String,
+
+        /// union: copy missing revisions in both directions; mirror: make the other archive a copy of this one,
+        /// which removes its revisions and channels this one does not have and then its unreferenced content
+        #[arg(long, default_value = "union")]
+        mode: SyncMode,
+
+        /// only sync this channel
+        #[arg(short, long)]
+        channel: Option<String>,
+
+        /// hash the content again when the archives hash differently, e.g. with another algorithm
+        #[arg(long)]
+        reencode: bool,
+
+        /// file with the password of the other archive; the password of this one otherwise
+        #[arg(long)]
+        to_password_file: Option<PathBuf>,
+    },
+
+    /// Remove the locks of the archive, e.g. after a process crashed on another host
+    BreakLock {
+        /// also remove the locks of processes which still run on this host
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// List what changed in a source dir since a revision; exits with 1 when something changed
+    Status {
+        /// path to the dir which was backed up
+        #[arg(short, long)]
+        source: String,
+
+        /// channel name
+        #[arg(short, long)]
+        channel: String,
+
+        /// the revision to compare with; the last one otherwise
+        #[arg(short, long)]
+        entry: Option<String>,
+
+        /// compare the files symlinks point to instead of the links, like backup --follow-symlinks
+        #[arg(long)]
+        follow_symlinks: bool,
+
+        /// hash every file, even when size, mtime and inode are unchanged
+        #[arg(long)]
+        rehash: bool,
+    },
+
+    /// Show what changed between two revisions of a channel
+    Diff {
+        /// channel name
+        #[arg(short, long)]
+        channel: String,
+
+        /// the older revision
+        old: String,
+
+        /// the newer revision
+        new: String,
+
+        /// only print the totals
+        #[arg(long)]
+        stat: bool,
+
+        /// print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+
     /// List all channels
     ListChannel {
         /// todo
@@ -104,41 +315,230 @@ fn main() -> anyhow::Result<()> {
 
 
     let cli = Cli::parse();
+    let password = read_password(cli.password_file.as_deref())?;
+    let password = password.as_deref();
 
     match &cli.subcommands.unwrap() {
-        SubCli::New => {
+        SubCli::New { compression, encrypt, private, hash, chunking, packing, layout } => {
             return BackupSession::init_session(
-                &PathBuf::from(cli.archive),
+                &cli.archive,
                 archive::ContentSettings {
-                    compression: ContentCompression::Bzip2 { level: 1 },
-                    hash_algo: HashAlgo::Sha256,
-                });
+                    compression: *compression,
+                    hash_algo: *hash,
+                    encryption: match encrypt {
+                        true => ContentEncryption::XChaCha20Poly1305,
+                        false => ContentEncryption::None,
+                    },
+                    addressing: match private {
+                        true => ContentAddressing::Keyed,
+                        false => ContentAddressing::Plain,
+                    },
+                    chunking: *chunking,
+                    packing: *packing,
+                    layout: *layout,
+                },
+                password);
         },
-        SubCli::Backup { source, channel } => {
-            let session = BackupSession::new(&PathBuf::from(cli.archive))?;
+        SubCli::Backup { source, channel, follow_symlinks, rehash } => {
+            let session = BackupSession::new(&cli.archive, password)?;
+            let (session, hash_cache) = match rehash {
+                true => (session, HashCache::default()),
+                false => HashCache::from_last_revision(session, channel)?,
+            };
             let channel_writer = ChannelWriter::new(session, channel)?;
-            return backup_command(&PathBuf::from(source), channel_writer);
+            return backup_command(&PathBuf::from(source), channel_writer, BackupOptions {
+                follow_symlinks: *follow_symlinks,
+                hash_cache: hash_cache,
+            });
         }
         SubCli::Restore {
             destination,
             channel,
             entry,
+            on_corruption,
+            on_conflict,
+            trust_mtime,
+            mirror,
+            dry_run,
+            include,
+            exclude,
+            include_regex,
+            exclude_regex,
+            strip_components,
+            paths,
         } => {
-            let session = BackupSession::new(&PathBuf::from(cli.archive))?;
+            let session = BackupSession::new_shared(&cli.archive, password)?;
             let channel_reader = ChannelReader::new(session, ChannelReaderOptions {
                 channel: channel.clone(),
                 entry: entry.clone(),
             })?;
 
-            return restore(channel_reader, &PathBuf::from(&destination));
+            return restore(channel_reader, &PathBuf::from(&destination), &RestoreOptions {
+                on_corruption: *on_corruption,
+                on_conflict: *on_conflict,
+                trust_mtime: *trust_mtime,
+                mirror: *mirror,
+                dry_run: *dry_run,
+                filter: PathFilter::new(paths, include, exclude, include_regex, exclude_regex)?,
+                strip_components: *strip_components,
+            });
         }
         SubCli::Verify => {
-            let session = BackupSession::new(&PathBuf::from(cli.archive))?;
+            let session = BackupSession::new_shared(&cli.archive, password)?;
+            let (_session, report) = archive::verify_all(session)?;
+
+            for problem in &report.problems {
+                problem.print();
+            }
+
+            println!(
+                "verified {} revisions ({} failed) and {} content files; {} problems",
+                report.revs_checked,
+                report.revs_failed,
+                report.content_checked,
+                report.problems.len()
+            );
+
+            if !report.is_ok() {
+                bail!("verify failed");
+            }
 
             return Ok(());
         }
+        SubCli::Gc { dry_run } => {
+            let session = BackupSession::new(&cli.archive, password)?;
+            return gc_command(session, *dry_run);
+        }
+        SubCli::MigrateLayout { layout } => {
+            let mut session = BackupSession::new(&cli.archive, password)?;
+            let moved = session.migrate_layout(*layout)?;
+            println!("moved {} content files into the {} layout", moved, layout.as_str());
+            return Ok(());
+        }
+        SubCli::Prune {
+            channel,
+            keep_last,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            dry_run,
+            gc,
+        } => {
+            let session = BackupSession::new(&cli.archive, password)?;
+            let policy = archive::RetentionPolicy {
+                keep_last: *keep_last,
+                keep_daily: *keep_daily,
+                keep_weekly: *keep_weekly,
+                keep_monthly: *keep_monthly,
+            };
+
+            let report = archive::prune_channel(&session, channel, &policy, *dry_run)?;
+
+            for rev in &report.revs {
+                println!(
+                    "{}  {}  {}",
+                    match rev.keep {
+                        true => "keep  ",
+                        false => "remove",
+                    },
+                    rev.time.format("%Y-%m-%d %H:%M:%S"),
+                    archive::key_name(&rev.path)
+                );
+            }
+
+            println!(
+                "{} revisions kept; {} removed",
+                report.kept().count(),
+                report.removed().count()
+            );
+
+            if *gc && !*dry_run {
+                return gc_command(session, false);
+            }
+
+            return Ok(());
+        }
+        SubCli::Sync { to, mode, channel, reencode, to_password_file } => {
+            let to_password = match to_password_file {
+                Some(to_password_file) => read_password(Some(to_password_file))?,
+                None => password.map(|x| x.to_owned()),
+            };
+
+            //a mirror only reads this archive
+            let mut session = match mode {
+                SyncMode::Mirror => BackupSession::new_shared(&cli.archive, password)?,
+                SyncMode::Union => BackupSession::new(&cli.archive, password)?,
+            };
+            let mut to_session = BackupSession::new(to, to_password.as_deref())?;
+
+            let report = archive::sync_archive(&session, &mut to_session, &archive::SyncOptions {
+                channel: channel.clone(),
+                remove_missing: *mode == SyncMode::Mirror,
+                reencode: *reencode,
+            })?;
+            print_sync_report(&report, &to_session);
+
+            if *mode == SyncMode::Union {
+                let report = archive::sync_archive(&to_session, &mut session, &archive::SyncOptions {
+                    channel: channel.clone(),
+                    remove_missing: false,
+                    reencode: *reencode,
+                })?;
+                print_sync_report(&report, &session);
+            }
+
+            //removed revisions may have been the last ones referencing some content
+            if !report.removed.is_empty() {
+                return gc_command(to_session, false);
+            }
+
+            return Ok(());
+        }
+        SubCli::BreakLock { force } => {
+            let report = archive::break_lock(&cli.archive, *force)?;
+            for (key, info) in &report.removed {
+                match info {
+                    Some(info) => println!("remove lock {} of {}", key, info.describe()),
+                    None => println!("remove lock {}", key),
+                }
+            }
+            for (key, info) in &report.kept {
+                println!("keep lock {} of {}; the process still runs, use --force to remove it anyway", key, info.describe());
+            }
+
+            return Ok(());
+        }
+        SubCli::Status { source, channel, entry, follow_symlinks, rehash } => {
+            let options = StatusOptions {
+                follow_symlinks: *follow_symlinks,
+                rehash: *rehash,
+            };
+
+            //like diff(1): 0 without changes, 1 with changes and 2 on errors; the session is closed before exiting
+            let status = BackupSession::new_shared(&cli.archive, password)
+                .and_then(|session| ChannelReader::new(session, ChannelReaderOptions {
+                    channel: channel.clone(),
+                    entry: entry.clone(),
+                }))
+                .and_then(|channel_reader| status_command(channel_reader, &PathBuf::from(source), &options));
+
+            match status {
+                Ok(0) => return Ok(()),
+                Ok(_) => std::process::exit(1),
+                Err(err) => {
+                    misc_helper::print_error_chain(&err);
+                    std::process::exit(2);
+                }
+            }
+        }
+        SubCli::Diff { channel, old, new, stat, json } => {
+            let session = BackupSession::new_shared(&cli.archive, password)?;
+            let (_session, diff) = archive::diff_revisions(session, channel, old, new)?;
+            print_diff(&diff, *stat, *json)?;
+            return Ok(());
+        }
         SubCli::ListChannel { todo: _ } => {
-            let session = BackupSession::new(&PathBuf::from(cli.archive))?;
+            let session = BackupSession::new_shared(&cli.archive, password)?;
 
             for channel in session.channel_names()? {
                 println!("{}", channel);
@@ -151,75 +551,598 @@ fn main() -> anyhow::Result<()> {
     return Ok(());
 }
 
-pub fn restore(channel_reader: archive::ChannelReader, restore_dir: &Path) -> anyhow::Result<()> {
-    for backup_info in channel_reader {
-        let Ok(backup_info) = backup_info else {
-            println!("RESTORE error entry");
+fn print_diff(diff: &archive::RevisionDiff, stat: bool, json: bool) -> anyhow::Result<()> {
+    if json {
+        let text = match stat {
+            true => serde_json::to_string_pretty(&diff.stat)?,
+            false => serde_json::to_string_pretty(diff)?,
+        };
+        println!("{}", text);
+        return Ok(());
+    }
+
+    if !stat {
+        for entry in &diff.entries {
+            let mut line = match &entry.from {
+                Some(from) => format!("{:<12}{} -> {}", entry.change.as_str(), from, entry.path),
+                None => format!("{:<12}{}", entry.change.as_str(), entry.path),
+            };
+            if !entry.meta.is_empty() {
+                line += &format!(" ({})", entry.meta.join(", "));
+            }
+            println!("{}", line);
+        }
+    }
+
+    println!(
+        "{} added, {} removed, {} modified, {} renamed, {} with changed metadata; +{} -{} bytes",
+        diff.stat.added,
+        diff.stat.removed,
+        diff.stat.modified,
+        diff.stat.renamed,
+        diff.stat.meta,
+        diff.stat.bytes_added,
+        diff.stat.bytes_removed
+    );
+
+    return Ok(());
+}
+
+/// The password of an encrypted archive; the first line of the password file
+/// or the environment variable BACKUPTOOL_PASSWORD.
+fn read_password(password_file: Option<&Path>) -> anyhow::Result<Option<String>> {
+    if let Some(password_file) = password_file {
+        let content = fs::read_to_string(password_file)
+            .with_context(|| format!("cannot read password file {}", password_file.to_string_lossy()))?;
+        return Ok(Some(content.lines().next().unwrap_or_default().to_owned()));
+    }
+
+    return Ok(std::env::var("BACKUPTOOL_PASSWORD").ok());
+}
+
+fn print_sync_report(report: &archive::SyncReport, target: &BackupSession) {
+    for rev_path in &report.copied {
+        println!("copy   {}", rev_path);
+    }
+    for rev_path in &report.removed {
+        println!("remove {}", rev_path);
+    }
+
+    println!(
+        "{} revisions copied to {}; {} removed; {} content files with {} bytes copied",
+        report.copied.len(),
+        target.backend().location(),
+        report.removed.len(),
+        report.content_copied,
+        report.bytes_copied
+    );
+}
+
+pub fn gc_command(session: BackupSession, dry_run: bool) -> anyhow::Result<()> {
+    let (_session, report) = archive::collect_garbage(session, dry_run)?;
+
+    for content in &report.removed {
+        let name = match &content.location {
+            ContentLocation::Loose(key) => key.clone(),
+            ContentLocation::Packed(location) => format!("{} (pack {})", content.id, location.pack),
+        };
+        match dry_run {
+            true => println!("unreferenced {}", name),
+            false => println!("remove       {}", name),
+        }
+    }
+
+    for key in &report.removed_temp {
+        match dry_run {
+            true => println!("unfinished   {}", key),
+            false => println!("remove       {}", key),
+        }
+    }
+
+    println!(
+        "{} referenced content files; {} unreferenced with {} bytes {}",
+        report.referenced,
+        report.removed.len(),
+        report.removed_bytes,
+        match dry_run {
+            true => "reclaimable",
+            false => "reclaimed",
+        }
+    );
+
+    return Ok(());
+}
+
+/// What restore does with a file whose content does not match its hash.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnCorruption {
+    /// stop the restore; the corrupt data is not written and an existing file is kept
+    Fail,
+    /// do not write the corrupt data, keep an existing file and restore the others
+    Skip,
+    /// write the corrupt data as far as it can be read and restore the others
+    Keep,
+}
+
+impl FromStr for OnCorruption {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<OnCorruption> {
+        return match value {
+            "fail" => Ok(OnCorruption::Fail),
+            "skip" => Ok(OnCorruption::Skip),
+            "keep" => Ok(OnCorruption::Keep),
+            _ => Err(anyhow!("unknown corruption handling {}", value)),
+        };
+    }
+}
+
+/// What restore does with an item which exists in the destination already.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnConflict {
+    Overwrite,
+    /// keep the existing one
+    Skip,
+    /// overwrite it when the restored one was modified later
+    Newer,
+    /// keep the existing one and restore next to it as `<name>.restored[-N]`
+    Rename,
+    /// stop the restore
+    Fail,
+}
+
+impl FromStr for OnConflict {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<OnConflict> {
+        return match value {
+            "overwrite" => Ok(OnConflict::Overwrite),
+            "skip" => Ok(OnConflict::Skip),
+            "newer" => Ok(OnConflict::Newer),
+            "rename" => Ok(OnConflict::Rename),
+            "fail" => Ok(OnConflict::Fail),
+            _ => Err(anyhow!("unknown conflict handling {}", value)),
+        };
+    }
+}
+
+/// The size of the file at `path` when it has the content of a backed up file,
+/// so it need not be restored.
+///
+/// Only a file with the size of the backed up one is hashed; with `trust_mtime`,
+/// one which also has its mtime is taken as unchanged without reading it.
+/// Revisions without a signature do not record the size, so their files are
+/// only hashed with `hash_unknown_size`.
+fn unchanged_size(
+    session: &BackupSession,
+    path: &Path,
+    checksum: &checksum::HashResult,
+    signature: Option<&FileSignature>,
+    trust_mtime: bool,
+    hash_unknown_size: bool,
+) -> Option<u64> {
+    let metadata = path.symlink_metadata().ok().filter(|x| x.is_file())?;
+
+    match signature {
+        Some(signature) => {
+            if metadata.len() != signature.size {
+                return None;
+            }
+            if trust_mtime && FileTime::from_last_modification_time(&metadata) == signature.mtime {
+                return Some(metadata.len());
+            }
+        }
+        None if !hash_unknown_size => return None,
+        None => {}
+    }
+
+    let file = fs::File::open(path).ok()?;
+    let calculated = session.content_hasher(checksum.algo()).stream(file).ok()?;
+    return match calculated.data() == checksum.data() {
+        true => Some(metadata.len()),
+        false => None,
+    };
+}
+
+/// `<path>.restored`, or `<path>.restored-N` with the first N which does not exist.
+fn free_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".restored");
+    let mut free = PathBuf::from(&name);
+
+    let mut count = 2;
+    while free.symlink_metadata().is_ok() {
+        let mut numbered = name.clone();
+        numbered.push(format!("-{}", count));
+        free = PathBuf::from(numbered);
+        count += 1;
+    }
+
+    return free;
+}
+
+/// Remove everything below `restore_dir` which is neither in `restored` nor a
+/// dir above it; returns the number of removed items. Only lists them with `dry_run`.
+fn remove_unrestored(restore_dir: &Path, restored: &HashSet<PathBuf>, dry_run: bool) -> anyhow::Result<usize> {
+    let Some(walker) = DirWalk::new_recursive(restore_dir) else {
+        return Ok(0);
+    };
+
+    let mut keep: HashSet<&Path> = restored.iter().flat_map(|x| x.ancestors()).collect();
+    keep.insert(restore_dir);
+    let mut removed = 0;
+
+    //a removed dir takes everything below it along
+    let unrestored: Vec<PathBuf> = walker
+        .filter(|x| !keep.contains(x.as_path()) && x.parent().is_some_and(|x| keep.contains(x)))
+        .collect();
+
+    for path in unrestored {
+        println!("remove {:?}", misc_helper::relative_path(restore_dir, &path));
+        removed += 1;
+        if dry_run {
             continue;
+        }
+
+        let is_dir = path.symlink_metadata().is_ok_and(|x| x.is_dir());
+        match is_dir {
+            true => fs::remove_dir_all(&path),
+            false => fs::remove_file(&path),
+        }.with_context(|| format!("cannot remove {}", path.to_string_lossy()))?;
+    }
+
+    return Ok(removed);
+}
+
+pub struct RestoreOptions {
+    pub on_corruption: OnCorruption,
+    pub on_conflict: OnConflict,
+    /// existing files with matching size and mtime are not hashed
+    pub trust_mtime: bool,
+    /// remove what is in the destination but was not restored
+    pub mirror: bool,
+    /// restore nothing; only list what mirror would remove
+    pub dry_run: bool,
+    /// only items matching it are restored
+    pub filter: PathFilter,
+    /// leading path components removed from every restored item
+    pub strip_components: usize,
+}
+
+pub fn restore(channel_reader: archive::ChannelReader, restore_dir: &Path, options: &RestoreOptions) -> anyhow::Result<()> {
+    let mut channel_reader = channel_reader;
+    let mut restored_dirs = Vec::new();
+    let mut corrupt_files = 0;
+    let mut skipped = Vec::new();
+    let mut unchanged_files = 0;
+    let mut unchanged_bytes = 0;
+    //existing path -> path the item was restored to instead
+    let mut renamed = BTreeMap::new();
+    //every path the restore writes; the rest is removed by mirror
+    let mut restored = HashSet::new();
+    //their paths are unknown, so mirror cannot tell what to keep
+    let mut unreadable_entries = 0;
+
+    while let Some(backup_info) = channel_reader.next() {
+        let backup_info = match backup_info {
+            Ok(backup_info) => backup_info,
+            Err(err) => {
+                misc_helper::print_error_chain(&err);
+                unreadable_entries += 1;
+                continue;
+            }
         };
 
+        let selected = options.filter.matches(&backup_info.relative_path);
+        let Some(relative_path) = misc_helper::strip_components(&backup_info.relative_path, options.strip_components) else {
+            continue;
+        };
         let restore_file = PathBuf::new()
             .join(&restore_dir)
-            .join(&backup_info.relative_path);
+            .join(&relative_path);
+
+        if selected {
+            restored.insert(restore_file.clone());
+        }
+        if options.dry_run {
+            continue;
+        }
+
+        //dirs above selected items are created on demand; they only get their meta when restore created them
+        if let ChannelItemKind::Dir = &backup_info.kind {
+            if selected {
+                println!("restore dir {:?}", &backup_info.relative_path);
+                misc_helper::create_dir_when_missing(&restore_file)?;
+            }
+            if selected || !restore_file.exists() {
+                restored_dirs.push((restore_file, backup_info.meta));
+            }
+            continue;
+        }
+
+        if !selected {
+            continue;
+        }
 
         let restore_subdirs = restore_file.parent().unwrap();
         misc_helper::create_dir_when_missing(restore_subdirs).unwrap();
 
-        if misc_helper::is_file_or_dir(&restore_file) {
-            println!(
-                "restore {:?} restore file or dir already exists",
-                &backup_info.relative_path
+        if let ChannelItemKind::File { checksum, signature, .. } = &backup_info.kind {
+            //skip and fail keep the existing file anyway, so it is only hashed when the size tells it may be unchanged
+            let hash_unknown_size = !matches!(options.on_conflict, OnConflict::Skip | OnConflict::Fail);
+            let unchanged = unchanged_size(
+                channel_reader.get_session(),
+                &restore_file,
+                checksum,
+                signature.as_ref(),
+                options.trust_mtime,
+                hash_unknown_size,
             );
+            if let Some(size) = unchanged {
+                println!("unchanged {:?}", &backup_info.relative_path);
+                unchanged_files += 1;
+                unchanged_bytes += size;
+                backup_info.meta.apply(&restore_file)?;
+                continue;
+            }
+        }
+
+        let mut restore_file = restore_file;
+        if let Ok(existing) = restore_file.symlink_metadata() {
+            match options.on_conflict {
+                OnConflict::Overwrite => {
+                    println!("restore {:?} over the existing one", &backup_info.relative_path);
+                }
+                OnConflict::Skip => {
+                    println!("skip {:?}: exists already", &backup_info.relative_path);
+                    skipped.push(relative_path);
+                    continue;
+                }
+                OnConflict::Newer => {
+                    let existing_mtime = FileTime::from_last_modification_time(&existing);
+                    if !backup_info.meta.mtime.is_some_and(|x| x > existing_mtime) {
+                        println!("skip {:?}: the existing one is not older", &backup_info.relative_path);
+                        skipped.push(relative_path);
+                        continue;
+                    }
+                    println!("restore {:?} over the older one", &backup_info.relative_path);
+                }
+                OnConflict::Rename => {
+                    let free_file = free_path(&restore_file);
+                    println!("restore {:?} as {:?}", &backup_info.relative_path, &free_file);
+                    renamed.insert(restore_file, free_file.clone());
+                    restored.insert(free_file.clone());
+                    restore_file = free_file;
+                }
+                OnConflict::Fail => {
+                    bail!("{} exists already", restore_file.to_string_lossy());
+                }
+            }
         } else {
             println!("restore {:?}", &backup_info.relative_path);
         }
 
-        misc_helper::copy_convert(
-            &backup_info.content_path,
-            &restore_file,
-            CopyAction::UnCompress,
-        )?;
+        match &backup_info.kind {
+            ChannelItemKind::Dir => {}
+            ChannelItemKind::File { contents, .. } => {
+                //an existing file is only replaced by intact content, or with keep by what could be read
+                let keep_corrupt = matches!(options.on_corruption, OnCorruption::Keep);
+                let corrupt = channel_reader.get_session().read_to_file(contents, &restore_file, keep_corrupt)?;
+                if !corrupt.is_empty() {
+                    corrupt_files += 1;
+                    println!("corrupt {:?}", &backup_info.relative_path);
+                    for err in &corrupt {
+                        misc_helper::print_error_chain(err);
+                    }
+
+                    match options.on_corruption {
+                        OnCorruption::Fail => {
+                            bail!("{:?} is corrupt", &backup_info.relative_path);
+                        }
+                        OnCorruption::Skip => {
+                            continue;
+                        }
+                        OnCorruption::Keep => {}
+                    }
+                }
+            }
+            ChannelItemKind::Symlink { target } => {
+                misc_helper::remove_file_when_exists(&restore_file)?;
+                misc_helper::create_symlink(target, &restore_file)?;
+                backup_info.meta.apply_symlink(&restore_file)?;
+                continue;
+            }
+            ChannelItemKind::Hardlink { target } => {
+                misc_helper::remove_file_when_exists(&restore_file)?;
+                let restored_target = misc_helper::strip_components(target, options.strip_components)
+                    .map(|x| restore_dir.join(x))
+                    .map(|x| renamed.get(&x).cloned().unwrap_or(x))
+                    .filter(|x| x.symlink_metadata().is_ok());
+                let Some(restored_target) = restored_target else {
+                    println!("skip {:?}: {:?} was not restored", &backup_info.relative_path, target);
+                    continue;
+                };
+                fs::hard_link(restored_target, &restore_file)
+                    .with_context(|| format!("cannot create hardlink {:?}", &backup_info.relative_path))?;
+                continue;
+            }
+            ChannelItemKind::Special { kind, rdev } => {
+                misc_helper::remove_file_when_exists(&restore_file)?;
+                if let Err(err) = kind.create(&restore_file, backup_info.meta.mode.unwrap_or(0o644), *rdev) {
+                    println!("skip {:?}: {}", &backup_info.relative_path, err);
+                    continue;
+                }
+            }
+        }
+
+        backup_info.meta.apply(&restore_file)?;
+    }
+
+    //a revision which is not fully restored must not decide what is removed
+    if options.mirror && (unreadable_entries > 0 || corrupt_files > 0) {
+        println!("nothing removed; {} entries of the revision cannot be read, {} files are corrupt", unreadable_entries, corrupt_files);
+    } else if options.mirror {
+        let removed = remove_unrestored(restore_dir, &restored, options.dry_run)?;
+        println!(
+            "{} items {}",
+            removed,
+            match options.dry_run {
+                true => "would be removed",
+                false => "removed",
+            }
+        );
+    }
+
+    //restoring the content of a dir touches its times; so apply them deepest first at the end
+    restored_dirs.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+    for (path, meta) in restored_dirs {
+        if path.is_dir() {
+            meta.apply(&path)?;
+        }
+    }
+
+    for path in &skipped {
+        println!("skipped      {:?}", path);
+    }
+    for (path, free_path) in &renamed {
+        println!("renamed      {:?} -> {:?}", misc_helper::relative_path(restore_dir, path), misc_helper::relative_path(restore_dir, free_path));
+    }
+    if unchanged_files > 0 {
+        println!("{} files were unchanged; {} bytes not restored", unchanged_files, unchanged_bytes);
+    }
+    if !skipped.is_empty() || !renamed.is_empty() {
+        println!("{} existing items skipped, {} restored under another name", skipped.len(), renamed.len());
+    }
+
+    if unreadable_entries > 0 {
+        bail!("{} entries of the revision cannot be read", unreadable_entries);
+    }
+    if corrupt_files > 0 {
+        bail!("{} corrupt files", corrupt_files);
     }
 
     return Ok(());
 }
 
+pub struct BackupOptions {
+    pub follow_symlinks: bool,
+    pub hash_cache: HashCache,
+}
+
 fn backup_file(
     channel_writer: Arc<Mutex<ChannelWriter>>,
     file_path: &Path,
     base_dir: &Path,
+    options: &BackupOptions,
 ) -> anyhow::Result<()> {
-    let Ok(metadata) = file_path.metadata() else {
+    let metadata = match options.follow_symlinks {
+        true => file_path.metadata().or_else(|_| file_path.symlink_metadata()),
+        false => file_path.symlink_metadata(),
+    };
+    let Ok(metadata) = metadata else {
         println!("cannot get metadata: {}", file_path.to_string_lossy());
         return Ok(());
     };
 
-    let settings = channel_writer
-        .lock()
-        .expect("cannot get settings from runtime session")
-        .get_session()
-        .get_settings()
-        .clone();
+    let (settings, key, backend, mut hasher, mut chunk_hasher) = {
+        let channel_writer = channel_writer
+            .lock()
+            .expect("cannot get settings from runtime session");
+        let session = channel_writer.get_session();
+        let hash_algo = session.get_settings().hash_algo;
+        (
+            session.get_settings().clone(),
+            session.get_key().cloned(),
+            session.backend().clone(),
+            session.content_hasher(hash_algo),
+            session.content_hasher(hash_algo),
+        )
+    };
+
+    let relative_path = misc_helper::relative_path(base_dir.as_ref(), file_path.as_ref());
+    let file_meta = FileMeta::from_metadata(file_path, &metadata, options.follow_symlinks);
 
     if metadata.is_file() {
-        let Ok(checksum) = checksum::new_hasher(settings.hash_algo).file(&file_path, ) else {
-            println!("cannot checksum file: {}", file_path.to_string_lossy());
-            return Ok(());
+        //a followed symlink is stored as a copy of its target
+        let inode = archive::inode_id(&metadata).filter(|_| !file_path.is_symlink());
+
+        //a link to a file which is already written is neither hashed nor stored again
+        if let Some(inode) = inode {
+            let mut channel_writer = channel_writer.lock()
+                .expect("writer worker error; cannot lock writer");
+            if let Some(hardlink_target) = channel_writer.written_hardlink_target(inode) {
+                println!("hardlink    {}    {}", hardlink_target.to_string_lossy(), file_path.to_string_lossy());
+                return channel_writer.add_hardlink(&relative_path, &hardlink_target);
+            }
+        }
+
+        let signature = FileSignature::from_metadata(&metadata);
+        let chunked = settings.chunking.is_chunked(signature.size);
+
+        //a cached entry is only reused when the file is still stored the same way
+        let cached = options.hash_cache
+            .get(&relative_path, &signature, settings.hash_algo)
+            .filter(|cached| cached.chunks.is_some() == chunked);
+
+        let (checksum, chunks) = match cached {
+            Some(cached) => (cached.checksum.clone(), cached.chunks.clone()),
+            None if chunked => {
+                let chunked_file = archive::write_chunked_file(
+                    file_path, backend.as_ref(), &settings, key.as_ref(), hasher.as_mut(), chunk_hasher.as_mut())?;
+                println!(
+                    "new chunks  {}/{}    {}",
+                    chunked_file.new_chunks,
+                    chunked_file.chunks.len(),
+                    file_path.to_string_lossy()
+                );
+                (chunked_file.checksum, Some(chunked_file.chunks))
+            }
+            None => {
+                let Ok(checksum) = hasher.file(&file_path) else {
+                    println!("cannot checksum file: {}", file_path.to_string_lossy());
+                    return Ok(());
+                };
+                (checksum, None)
+            }
         };
         let checksum_str = checksum.to_string_short();
 
-        let action = channel_writer.lock()
-            .expect("writer worker error; cannot lock writer")
-            .deref_mut()
-            .add_file(
-                misc_helper::relative_path(base_dir.as_ref(), file_path.as_ref()).as_path(),
-                &checksum,
-            )?;
+        let action = {
+            let mut channel_writer = channel_writer.lock()
+                .expect("writer worker error; cannot lock writer");
+
+            //another link may have been written while this one was hashed
+            let hardlink_target = inode
+                .and_then(|inode| channel_writer.hardlink_target(inode, &relative_path));
+
+            if let Some(hardlink_target) = hardlink_target {
+                println!("hardlink    {}    {}", hardlink_target.to_string_lossy(), file_path.to_string_lossy());
+                return channel_writer.add_hardlink(&relative_path, &hardlink_target);
+            }
+
+            if let Some(chunks) = &chunks {
+                println!("chunked     {}    {}", checksum_str, file_path.to_string_lossy());
+                return channel_writer.add_chunked_file(relative_path.as_path(), &checksum, chunks, &signature, &file_meta);
+            }
+
+            channel_writer.add_file(relative_path.as_path(), &checksum, &signature, &file_meta)?
+        };
 
         match action {
             ChannelWriterAdd::HashFile(hash_path) => {
                 println!("new file    {}    {}", checksum_str, file_path.to_string_lossy());
-                misc_helper::copy_convert(&file_path, &hash_path, CopyAction::Compress)?;
+                archive::write_content_file(&file_path, &checksum, hasher.as_mut(), backend.as_ref(), &hash_path, &settings, key.as_ref())?;
+            }
+            ChannelWriterAdd::PackFile => {
+                //compress outside of the lock; only appending to the pack is serialized
+                let blob = archive::encode_content_file(&file_path, &checksum, hasher.as_mut(), &settings, key.as_ref())?;
+                let added = channel_writer.lock()
+                    .expect("writer worker error; cannot lock writer")
+                    .add_packed_content(&checksum, &blob)?;
+                match added {
+                    true => println!("new packed  {}    {}", checksum_str, file_path.to_string_lossy()),
+                    false => println!("skip file   {}    {}", checksum_str, file_path.to_string_lossy()),
+                }
             }
             ChannelWriterAdd::AlreadyExist => {
                 println!("skip file   {}    {}", checksum_str, file_path.to_string_lossy());
@@ -232,7 +1155,20 @@ fn backup_file(
         channel_writer.lock()
             .expect("writer worker error; cannot lock writer")
             .deref_mut()
-            .add_dir(file_path)?
+            .add_dir(relative_path.as_path(), &file_meta)?
+    } else if metadata.is_symlink() {
+        let target = fs::read_link(file_path)?;
+        println!("symlink     {} -> {}", file_path.to_string_lossy(), target.to_string_lossy());
+        channel_writer.lock()
+            .expect("writer worker error; cannot lock writer")
+            .deref_mut()
+            .add_symlink(relative_path.as_path(), &target, &file_meta)?
+    } else if let Some(kind) = SpecialKind::from_metadata(&metadata) {
+        println!("{:<12}{}", kind.as_str(), file_path.to_string_lossy());
+        channel_writer.lock()
+            .expect("writer worker error; cannot lock writer")
+            .deref_mut()
+            .add_special(relative_path.as_path(), kind, misc_helper::rdev(&metadata), &file_meta)?
     }
     else {
         println!("invalid     {}", file_path.to_string_lossy());
@@ -241,12 +1177,26 @@ fn backup_file(
     return Ok(());
 }
 
+/// List the changes of `src_dir` since the revision of `channel_reader`; returns their number.
+pub fn status_command(channel_reader: ChannelReader, src_dir: &Path, options: &StatusOptions) -> anyhow::Result<usize> {
+    let (_session, report) = archive::source_status(channel_reader, src_dir, options)?;
+
+    for entry in &report.entries {
+        println!("{:<12}{}", entry.change.as_str(), entry.path.to_string_lossy());
+    }
+    println!("{} new, {} modified, {} deleted", report.new, report.modified, report.deleted);
+
+    return Ok(report.entries.len());
+}
+
 pub fn backup_command(
     src_dir: &Path,
     channel_writer: ChannelWriter,
+    options: BackupOptions,
 ) -> anyhow::Result<()> {
     let thread_count = 4usize;
     let channel_writer = Arc::new(Mutex::new(channel_writer));
+    let options = Arc::new(options);
 
     let (send_channel, recv_channel) = crossbeam::channel::bounded::<Option<PathBuf>>(1);
 
@@ -255,16 +1205,25 @@ pub fn backup_command(
         let channel_writer = channel_writer.clone();
         let recv_channel = recv_channel.clone();
         let src_dir = src_dir.to_owned();
+        let options = options.clone();
         let handle = thread::spawn(move || {
             while let Some(src_file) = recv_channel.recv().unwrap() {
-                backup_file(channel_writer.clone(), &src_file, &src_dir).unwrap();
+                backup_file(channel_writer.clone(), &src_file, &src_dir, &options).unwrap();
             }
         });
 
         join_handles.push(handle);
     }
 
-    for entry in DirWalk::new_recursive(src_dir).unwrap() {
+    let dirwalk = DirWalk::new(DirWalkParameters {
+        root_dir: src_dir.to_owned(),
+        recursive: true,
+        follow_symlinks: options.follow_symlinks,
+        filter: None,
+    })
+    .ok_or(anyhow!("cannot read source dir {}", src_dir.to_string_lossy()))?;
+
+    for entry in dirwalk {
         send_channel.send(Some(entry))?;
     }
     for _ in join_handles.iter() {
@@ -274,5 +1233,12 @@ pub fn backup_command(
         handle.join().expect("join worker failed");
     }
 
+    //the revision only appears now; an aborted backup leaves none behind
+    let channel_writer = Arc::into_inner(channel_writer)
+        .expect("writer worker still running")
+        .into_inner()
+        .expect("writer worker error; cannot lock writer");
+    channel_writer.commit()?;
+
     return Ok(());
 }
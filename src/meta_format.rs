@@ -3,7 +3,7 @@ use itertools::Itertools;
 use sha2::{digest::FixedOutputReset, Digest, Sha256};
 use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 
-use crate::checksum::{self, HashResult};
+use crate::checksum::{self, HashAlgo, HashResult};
 
 mod reserved_keywords {
     pub const SEPERATOR: &str = ":";
@@ -22,6 +22,11 @@ pub struct ReaderEntry {
     pub depth: usize,
 }
 
+/// Whether `key` is the marker which ends every file; the reader returns it like any entry.
+pub fn is_end_marker(key: &str) -> bool {
+    return key == reserved_keywords::END_MARKER;
+}
+
 impl<T: Read> Reader<T> {
     pub fn new(reader: T) -> Reader<T> {
         return Reader {
@@ -105,8 +110,8 @@ impl<T: Write> Writer<T> {
 
     fn write_raw(&mut self, text: &str) -> anyhow::Result<()> {
         Digest::update(&mut self.digest, text.as_bytes());
-        self.writer.write(text.as_bytes())?;
-        self.writer.write(b"\n")?;
+        self.writer.write_all(text.as_bytes())?;
+        self.writer.write_all(b"\n")?;
         self.bytes_written += text.as_bytes().len();
         return Ok(());
     }
@@ -129,24 +134,23 @@ impl<T: Write> Writer<T> {
         self.depth -= 1;
         self.any_writes = true;
     }
-}
 
-impl<T: Write> Drop for Writer<T> {
-    fn drop(&mut self) {
-        if self.bytes_written == 0 {
-            return;
+    /// Write the end marker and return the inner writer; a file without the marker is incomplete.
+    pub fn finish(mut self) -> anyhow::Result<T> {
+        if self.bytes_written > 0 {
+            let hashsum = self.digest.finalize_fixed_reset();
+            let hashsum = HashResult::from_data(HashAlgo::Sha256, &hashsum.to_vec());
+
+            self.write_raw(&format!(
+                "{}{}{}",
+                reserved_keywords::END_MARKER,
+                reserved_keywords::SEPERATOR,
+                hashsum.to_hex()
+            ))?;
         }
 
-        let hashsum = self.digest.finalize_fixed_reset();
-        let hashsum = HashResult::from_data(&hashsum.to_vec());
-
-        self.write_raw(&format!(
-            "{}{}{}",
-            reserved_keywords::END_MARKER,
-            reserved_keywords::SEPERATOR,
-            hashsum.to_string()
-        ))
-        .expect("meta format cannot write end marker");
+        return self.writer.into_inner()
+            .map_err(|err| anyhow!("meta format cannot flush: {}", err.error()));
     }
 }
 
@@ -172,8 +176,8 @@ pub fn verify<T: Read>(reader: T) -> anyhow::Result<()> {
         ));
     };
 
-    let read_hashsum = HashResult::from_hex_string(&read_hashsum)?;
-    let calc_hashsum = HashResult::from_data(&hasher.finalize().to_vec());
+    let read_hashsum = HashResult::from_hex_string(HashAlgo::Sha256, &read_hashsum)?;
+    let calc_hashsum = HashResult::from_data(HashAlgo::Sha256, &hasher.finalize().to_vec());
 
     if read_hashsum.data() == calc_hashsum.data() {
         return Ok(());
@@ -1,10 +1,7 @@
 //Misc helper
 //  later move this to another place
 
-use anyhow::anyhow;
-use bzip2::bufread::BzDecoder;
-use bzip2::read::BzEncoder;
-use bzip2::Compression;
+use anyhow::{anyhow, Context};
 use std::fs;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Read, Write};
@@ -28,6 +25,16 @@ pub fn relative_path(base_path: &Path, sub_path: &Path) -> PathBuf {
     return relative;
 }
 
+/// `path` without its first `count` components; None when nothing remains.
+pub fn strip_components(path: &Path, count: usize) -> Option<PathBuf> {
+    let stripped: PathBuf = path.components().skip(count).collect();
+    if count > 0 && stripped.as_os_str().is_empty() {
+        return None;
+    }
+
+    return Some(stripped);
+}
+
 const fn mebibyte(value: usize) -> usize {
     return value * 1024 * 1024;
 }
@@ -63,52 +70,67 @@ pub fn create_dir_when_missing(path: &Path) -> anyhow::Result<()> {
     return Ok(());
 }
 
-pub enum CopyAction {
-    Compress,
-    UnCompress,
+/// Anonymous temporary file; removed as soon as it is closed.
+pub fn temp_file() -> anyhow::Result<File> {
+    let path = std::env::temp_dir().join(format!("backuptool-{:016x}", rand::random::<u64>()));
+    let file = File::options().read(true).write(true).create_new(true).open(&path)
+        .with_context(|| format!("cannot create temporary file {}", path.to_string_lossy()))?;
+    let _ = fs::remove_file(&path);
+    return Ok(file);
 }
 
-pub fn copy_convert(src: &Path, dst: &Path, options: CopyAction) -> anyhow::Result<()> {
-    let Ok(src_file) = File::open(src) else {
-        return Err(anyhow!("cannot open source file {}", src.to_string_lossy()));
-    };
-    let Ok(dst_file) = File::create(&dst) else {
-        return Err(anyhow!(
-            "cannot open destination file {}",
-            src.to_string_lossy()
-        ));
+pub fn remove_file_when_exists(path: &Path) -> anyhow::Result<()> {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return Ok(());
     };
 
-    let mut src_file = match options {
-        CopyAction::Compress => {
-            Box::new(BzEncoder::new(src_file, Compression::best())) as Box<dyn Read>
-        }
-        CopyAction::UnCompress => {
-            Box::new(BzDecoder::new(BufReader::new(src_file))) as Box<dyn Read>
-        }
-    };
+    if metadata.is_dir() {
+        return Err(anyhow!("{} is a dir", path.to_string_lossy()));
+    }
+
+    fs::remove_file(path)?;
+
+    return Ok(());
+}
+
+pub fn create_symlink(target: &Path, link: &Path) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, link)?;
+
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_file(target, link)?;
 
-    let mut dst_file = BufWriter::new(dst_file);
+    return Ok(());
+}
+
+pub fn rdev(metadata: &fs::Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        return metadata.rdev();
+    }
 
-    // create out file
+    #[cfg(not(unix))]
+    return 0;
+}
+
+/// Copy everything from `src` to `dst`; returns the number of bytes copied.
+pub fn copy_stream<R: Read + ?Sized, W: Write + ?Sized>(src: &mut R, dst: &mut W) -> anyhow::Result<u64> {
     let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
+    let mut count = 0u64;
 
     loop {
-        let Ok(read_size) = src_file.read(&mut buffer[..]) else {
-            return Err(anyhow!(
-                "cannot read from source file {}",
-                src.to_string_lossy()
-            ));
-        };
+        let read_size = src.read(&mut buffer[..])?;
 
         if read_size == 0 {
             break;
         }
 
-        dst_file.write(&buffer[0..read_size])?;
+        dst.write_all(&buffer[0..read_size])?;
+        count += read_size as u64;
     }
 
-    return Ok(());
+    return Ok(count);
 }
 
 pub fn print_error_chain(err: &anyhow::Error) {
@@ -121,3 +143,32 @@ pub fn print_error_chain(err: &anyhow::Error) {
         indent += 1;
     }
 }
+
+/// Name of this host; empty when it cannot be found.
+pub fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buffer = vec![0u8; 256];
+        let ret = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+        if ret == 0 {
+            let end = buffer.iter().position(|x| *x == 0).unwrap_or(buffer.len());
+            return String::from_utf8_lossy(&buffer[0..end]).to_string();
+        }
+    }
+
+    return std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_default();
+}
+
+/// Whether a process with `pid` runs on this host; assumed true where it cannot be checked.
+pub fn process_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+        return ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    }
+
+    #[cfg(not(unix))]
+    return true;
+}
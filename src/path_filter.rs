@@ -0,0 +1,92 @@
+use std::path::{Component, Path, PathBuf};
+use anyhow::Context;
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
+
+/// Selects paths of a revision by prefix, by glob patterns and by regexes.
+///
+/// A pattern without `/` matches any single component, like `*.jpg`; one with
+/// `/` matches from the root of the revision, like `photos/2023-*`. A regex is
+/// searched in the path from the root, like `^photos/20(19|2[0-3])-`. A pattern
+/// or regex matching a dir also matches everything below it.
+pub struct PathFilter {
+    paths: Vec<PathBuf>,
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>,
+    include_regexes: Vec<Regex>,
+    exclude_regexes: Vec<Regex>,
+}
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+fn parse_patterns(patterns: &[String]) -> anyhow::Result<Vec<Pattern>> {
+    return patterns
+        .iter()
+        .map(|x| Pattern::new(x.trim_start_matches('/')).with_context(|| format!("invalid pattern {}", x)))
+        .collect();
+}
+
+fn pattern_matches(pattern: &Pattern, path: &Path) -> bool {
+    if !pattern.as_str().contains('/') {
+        return path.components().any(|x| pattern.matches_with(&x.as_os_str().to_string_lossy(), MATCH_OPTIONS));
+    }
+
+    return path.ancestors().any(|x| pattern.matches_path_with(x, MATCH_OPTIONS));
+}
+
+fn parse_regexes(regexes: &[String]) -> anyhow::Result<Vec<Regex>> {
+    return regexes
+        .iter()
+        .map(|x| Regex::new(x).with_context(|| format!("invalid regex {}", x)))
+        .collect();
+}
+
+fn regex_matches(regex: &Regex, path: &Path) -> bool {
+    return path.ancestors()
+        .filter(|x| !x.as_os_str().is_empty())
+        .any(|x| regex.is_match(&x.to_string_lossy()));
+}
+
+impl PathFilter {
+    /// Paths are relative to the root of the revision; a leading `/` is ignored.
+    pub fn new(
+        paths: &[PathBuf],
+        includes: &[String],
+        excludes: &[String],
+        include_regexes: &[String],
+        exclude_regexes: &[String],
+    ) -> anyhow::Result<PathFilter> {
+        return Ok(PathFilter {
+            paths: paths.iter().map(|x| x.components().filter(|x| matches!(x, Component::Normal(_))).collect()).collect(),
+            includes: parse_patterns(includes)?,
+            excludes: parse_patterns(excludes)?,
+            include_regexes: parse_regexes(include_regexes)?,
+            exclude_regexes: parse_regexes(exclude_regexes)?,
+        });
+    }
+
+    /// Whether `path` is below one of the paths, matches an include pattern or
+    /// regex and no exclude pattern or regex.
+    ///
+    /// Without paths or include patterns and regexes everything is included.
+    pub fn matches(&self, path: &Path) -> bool {
+        if !self.paths.is_empty() && !self.paths.iter().any(|x| path.starts_with(x)) {
+            return false;
+        }
+
+        let any_include = !self.includes.is_empty() || !self.include_regexes.is_empty();
+        if any_include
+            && !self.includes.iter().any(|x| pattern_matches(x, path))
+            && !self.include_regexes.iter().any(|x| regex_matches(x, path))
+        {
+            return false;
+        }
+
+        return !self.excludes.iter().any(|x| pattern_matches(x, path))
+            && !self.exclude_regexes.iter().any(|x| regex_matches(x, path));
+    }
+}
@@ -67,38 +67,100 @@ mod tests {
             return self;
         }
 
+        /// Run the tool on the archive of the test with `args`.
+        fn run(&self, args: &[&str]) -> assert_cmd::assert::Assert {
+            return run_archive(&self.archive, args);
+        }
+
+        /// Back up the source dir into the channel main.
+        fn backup(&self, args: &[&str]) -> assert_cmd::assert::Assert {
+            let source = format!("--source={}", self.src.to_string_lossy());
+            return self.run(&[&["backup", &source, "--channel=main"], args].concat());
+        }
+
+        /// Restore the channel main into `dst`.
+        fn restore_to(&self, dst: &Path, args: &[&str]) -> assert_cmd::assert::Assert {
+            let destination = format!("--destination={}", dst.to_string_lossy());
+            return self.run(&[&["restore", &destination, "--channel=main"], args].concat());
+        }
+
+        fn restore(&self, args: &[&str]) -> assert_cmd::assert::Assert {
+            return self.restore_to(&self.dst, args);
+        }
+
+        /// A path inside the temp dir which does not exist yet.
+        fn tmp_path(&self) -> PathBuf {
+            return self.tmp_instance.path().join(format!("tmp-{:016x}", rand::random::<u64>()));
+        }
+
+        /// Sorted names of the revisions of the channel main.
+        fn revisions(&self) -> Vec<String> {
+            let mut ret: Vec<String> = std::fs::read_dir(self.archive.join("channels").join("main"))
+                .unwrap()
+                .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+                .filter(|x| !x.starts_with(".tmp-"))
+                .collect();
+            ret.sort();
+            return ret;
+        }
+
         fn archive_new(self) -> Self {
-            let output = Command::cargo_bin("backup").unwrap()
-            .arg(format!("--archive={}/", self.archive.to_string_lossy()))
-            .arg("new")
-            .unwrap();
+            self.run(&["new"]).success();
+            return self;
+        }
 
+        fn archive_new_with(self, args: &[&str]) -> Self {
+            self.run(&[&["new"], args].concat()).success();
             return self;
         }
 
         fn archive_backup(self) -> Self {
-            let output = Command::cargo_bin("backup").unwrap()
-            .arg(format!("--archive={}/", self.archive.to_string_lossy()))
-            .arg("backup")
-            .arg(format!("--source={}", self.src.to_string_lossy()))
-            .arg(format!("--channel=main"))
-            .unwrap();
-
+            self.backup(&[]).success();
             return self;
         }
 
         fn archive_restore(self) -> Self {
-            let output = Command::cargo_bin("backup").unwrap()
-            .arg(format!("--archive={}/", self.archive.to_string_lossy()))
-            .arg("restore")
-            .arg(format!("--destination={}", self.dst.to_string_lossy()))
-            .arg(format!("--channel=main"))
-            .unwrap();
+            self.restore(&[]).success();
+            return self;
+        }
 
+        fn archive_gc(self) -> Self {
+            self.run(&["gc"]).success();
             return self;
         }
 
+        fn archive_verify(&self) -> assert_cmd::assert::Assert {
+            return self.run(&["verify"]);
+        }
+    }
+
+    /// Run the tool on `archive`, which may also be an url.
+    fn run_archive(archive: &Path, args: &[&str]) -> assert_cmd::assert::Assert {
+        return Command::cargo_bin("backuptool").unwrap()
+            .env_remove("BACKUPTOOL_PASSWORD")
+            .arg(format!("--archive={}", archive.to_string_lossy()))
+            .args(args)
+            .assert();
+    }
+
+    fn stdout(assert: assert_cmd::assert::Assert) -> String {
+        return String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    }
+
+    fn stderr(assert: assert_cmd::assert::Assert) -> String {
+        return String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    }
 
+    /// Sorted paths of the files below `dir`, relative to it.
+    fn files_below(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = crate::dirwalk::DirWalk::new_recursive(dir)
+            .into_iter()
+            .flatten()
+            .filter(|x| x.is_file())
+            .map(|x| misc_helper::relative_path(dir, &x).to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        return files;
     }
 
     //testdata
@@ -126,6 +188,1051 @@ mod tests {
             println!("- {:?}", testdir.tmp_instance.into_path());
         }
     }
-}
 
+    #[test]
+    fn verify() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        testdir.archive_verify().success();
+
+        //corrupt one content file
+        let content_file = std::fs::read_dir(testdir.archive.join("content"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        std::fs::write(&content_file, b"corrupt").unwrap();
+
+        testdir.archive_verify().failure();
+
+        //a broken revision is reported and the others are still checked
+        let testdir = testdir.archive_backup();
+        let rev = testdir.archive.join("channels").join("main").join(&testdir.revisions()[0]);
+        std::fs::write(&rev, b"\xff\xfe not a revision").unwrap();
+
+        let output = stdout(testdir.archive_verify().failure());
+        assert!(output.contains("verified 2 revisions (1 failed)"), "{}", output);
+    }
+
+    #[test]
+    fn gc() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        let garbage = testdir.archive.join("content").join("00");
+        std::fs::write(&garbage, b"unreferenced").unwrap();
+
+        let testdir = testdir
+            .archive_gc()
+            .archive_restore();
+
+        assert!(!garbage.exists());
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+
+        //the content of a broken revision is never taken as garbage
+        std::fs::write(testdir.src.join("new_file.txt"), b"only in the broken revision").unwrap();
+        let testdir = testdir.archive_backup();
+        let content_count = || std::fs::read_dir(testdir.archive.join("content")).unwrap().count();
+        let count_before = content_count();
+        for rev in testdir.revisions() {
+            std::fs::write(testdir.archive.join("channels").join("main").join(rev), "file:partial\n").unwrap();
+        }
+
+        let output = stderr(testdir.run(&["gc"]).failure());
+        assert!(output.contains("no content is removed while a revision is unreadable"), "{}", output);
+        assert_eq!(content_count(), count_before);
+    }
+
+    #[test]
+    fn prune() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup()
+            .archive_backup();
+
+        assert_eq!(testdir.revisions().len(), 2);
+
+        //without any keep rule nothing is removed
+        testdir.run(&["prune", "--channel=main"]).failure();
+        testdir.run(&["prune", "--channel=main", "--keep-last=1", "--gc"]).success();
+
+        assert_eq!(testdir.revisions().len(), 1);
+
+        let testdir = testdir.archive_restore();
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+    }
+
+    #[test]
+    fn revision_order() {
+        use chrono::Datelike;
+
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        //older versions did not pad month and day, e.g. 2026918; such names sort after padded ones of the same year
+        let channel_dir = testdir.archive.join("channels").join("main");
+        let old_style = channel_dir.join(format!("{}918_1200_00_0123456789abcdef", chrono::Utc::now().year()));
+        std::fs::rename(channel_dir.join(&testdir.revisions()[0]), &old_style).unwrap();
+        filetime::set_file_mtime(&old_style, filetime::FileTime::from_unix_time(1_000_000_000, 0)).unwrap();
+
+        std::fs::write(testdir.src.join("new_file.txt"), b"only in the newer revision").unwrap();
+        let testdir = testdir
+            .archive_backup()
+            .archive_restore();
+
+        assert!(testdir.revisions().last().unwrap().ends_with("0123456789abcdef"));
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+
+        //revisions of the same second are ordered by their file time; prune keeps the one restore takes
+        let latest = testdir.revisions().into_iter().find(|x| !x.ends_with("0123456789abcdef")).unwrap();
+        let newer = channel_dir.join("20300101_1200_00_0000000000000000");
+        let older = channel_dir.join("20300101_1200_00_ffffffffffffffff");
+        std::fs::rename(channel_dir.join(latest), &newer).unwrap();
+        std::fs::rename(&old_style, &older).unwrap();
+        filetime::set_file_mtime(&newer, filetime::FileTime::from_unix_time(2_000_000_000, 0)).unwrap();
+        filetime::set_file_mtime(&older, filetime::FileTime::from_unix_time(1_900_000_000, 0)).unwrap();
+
+        testdir.run(&["prune", "--channel=main", "--keep-last=1"]).success();
+        assert_eq!(testdir.revisions(), ["20300101_1200_00_0000000000000000"]);
+        let dst = testdir.tmp_path();
+        testdir.restore_to(&dst, &[]).success();
+        assert!(!dir_diff::is_different(&testdir.src, &dst).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_meta() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>();
+
+        let src_file = testdir.src.join("root.txt");
+        let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 123_456_789);
+        std::fs::set_permissions(&src_file, std::fs::Permissions::from_mode(0o640)).unwrap();
+        filetime::set_file_mtime(&src_file, mtime).unwrap();
+
+        let testdir = testdir
+            .archive_new()
+            .archive_backup()
+            .archive_restore();
+
+        let dst_meta = std::fs::metadata(testdir.dst.join("root.txt")).unwrap();
+        assert_eq!(dst_meta.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(filetime::FileTime::from_last_modification_time(&dst_meta), mtime);
+    }
+
+    #[test]
+    fn empty_dir() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>();
+
+        misc_helper::create_dir_when_missing(&testdir.src.join("empty_dir")).unwrap();
+        misc_helper::create_dir_when_missing(&testdir.src.join("level1").join("empty_sub")).unwrap();
+
+        let testdir = testdir
+            .archive_new()
+            .archive_backup()
+            .archive_restore();
+
+        assert!(misc_helper::is_dir(&testdir.dst.join("empty_dir")));
+        assert!(misc_helper::is_dir(&testdir.dst.join("level1").join("empty_sub")));
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+
+        //revisions must not contain the source path
+        let channel_dir = testdir.archive.join("channels").join("main");
+        for rev in std::fs::read_dir(&channel_dir).unwrap() {
+            let content = std::fs::read_to_string(rev.unwrap().path()).unwrap();
+            assert!(!content.contains(&*testdir.src.to_string_lossy()));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_and_special_files() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>();
+
+        std::fs::hard_link(testdir.src.join("root.txt"), testdir.src.join("hardlink.txt")).unwrap();
+        std::os::unix::fs::symlink("level1", testdir.src.join("symlink_dir")).unwrap();
+        std::os::unix::fs::symlink("/nonexistent", testdir.src.join("symlink_broken")).unwrap();
+        let fifo = std::ffi::CString::new(testdir.src.join("fifo").to_string_lossy().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        let testdir = testdir
+            .archive_new()
+            .archive_backup()
+            .archive_restore();
+
+        let root = std::fs::metadata(testdir.dst.join("root.txt")).unwrap();
+        let hardlink = std::fs::metadata(testdir.dst.join("hardlink.txt")).unwrap();
+        assert_eq!(root.ino(), hardlink.ino());
+
+        assert_eq!(std::fs::read_link(testdir.dst.join("symlink_dir")).unwrap(), PathBuf::from("level1"));
+        assert_eq!(std::fs::read_link(testdir.dst.join("symlink_broken")).unwrap(), PathBuf::from("/nonexistent"));
+        assert!(std::fs::symlink_metadata(testdir.dst.join("fifo")).unwrap().file_type().is_fifo());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn xattrs() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>();
+
+        let src_file = testdir.src.join("root.txt");
+        if xattr::set(&src_file, "user.backuptool", b"value").is_err() {
+            println!("the file system of the temp dir has no user xattrs");
+            return;
+        }
+        std::os::unix::fs::symlink("root.txt", testdir.src.join("symlink")).unwrap();
+
+        let testdir = testdir
+            .archive_new()
+            .archive_backup()
+            .archive_restore();
+
+        assert_eq!(xattr::get(testdir.dst.join("root.txt"), "user.backuptool").unwrap(), Some(b"value".to_vec()));
+
+        //the link has no xattrs of its own; the ones of its target belong to the target only
+        let rev = std::fs::read_to_string(testdir.archive.join("channels").join("main").join(&testdir.revisions()[0])).unwrap();
+        assert_eq!(rev.matches("xattr:user.backuptool=").count(), 1);
+    }
+
+    #[test]
+    fn mixed_compression() {
+        use std::io::Read;
+
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new_with(&["--compression=zstd:5"])
+            .archive_backup();
+
+        //change the codec of an existing archive
+        let settings_file = testdir.archive.join("settings.json");
+        let settings = std::fs::read_to_string(&settings_file).unwrap();
+        let mut settings: serde_json::Value = serde_json::from_str(&settings).unwrap();
+        settings["compression"] = serde_json::json!("Lz4");
+        std::fs::write(&settings_file, settings.to_string()).unwrap();
+
+        std::fs::write(testdir.src.join("new_file.txt"), b"written with lz4").unwrap();
+
+        //content of older versions is plain bzip2 without header
+        let root_txt = std::fs::read(testdir.src.join("root.txt")).unwrap();
+        let mut legacy = Vec::new();
+        bzip2::read::BzEncoder::new(&root_txt[..], bzip2::Compression::best())
+            .read_to_end(&mut legacy)
+            .unwrap();
+        for entry in std::fs::read_dir(testdir.archive.join("content")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap().to_string_lossy().starts_with("795d0f0b") {
+                std::fs::write(path, &legacy).unwrap();
+            }
+        }
+
+        let testdir = testdir
+            .archive_backup()
+            .archive_restore();
+
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+        testdir.archive_verify().success();
+    }
+
+    #[test]
+    fn encryption() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>();
+
+        let password_file = testdir.tmp_instance.path().join("password");
+        std::fs::write(&password_file, "secret\n").unwrap();
+        let wrong_password_file = testdir.tmp_instance.path().join("wrong_password");
+        std::fs::write(&wrong_password_file, "wrong\n").unwrap();
+        let password = format!("--password-file={}", password_file.to_string_lossy());
+        let wrong_password = format!("--password-file={}", wrong_password_file.to_string_lossy());
+
+        //an encrypted archive cannot be created without a password
+        testdir.run(&["new", "--encrypt"]).failure();
+        testdir.run(&["new", "--encrypt", &password]).success();
+
+        testdir.backup(&[]).failure();
+        testdir.backup(&[&wrong_password]).failure();
+        testdir.backup(&[&password]).success();
+
+        //neither content nor revisions contain plain data
+        let root_txt = std::fs::read(testdir.src.join("root.txt")).unwrap();
+        for dir in ["content", "channels/main"] {
+            for entry in std::fs::read_dir(testdir.archive.join(dir)).unwrap() {
+                let data = std::fs::read(entry.unwrap().path()).unwrap();
+                assert!(data.starts_with(b"BKE"));
+                assert!(!data.windows(root_txt.len()).any(|part| part == root_txt));
+                assert!(!data.windows(8).any(|part| part == b"root.txt"));
+            }
+        }
+
+        testdir.run(&["verify", &password]).success();
+        testdir.restore(&[&wrong_password]).failure();
+        testdir.restore(&[&password]).success();
+
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+    }
+
+    #[test]
+    fn aborted_encryption() {
+        use std::io::{Read, Write};
+        use crate::archive::{decrypt_reader, ContentKey, EncryptWriter};
+
+        let key = ContentKey::generate();
+        let data: Vec<u8> = (0..200_000u32).map(|x| (x % 251) as u8).collect();
+        let decrypt = |encrypted: Vec<u8>| {
+            let mut plain = Vec::new();
+            decrypt_reader(Box::new(std::io::Cursor::new(encrypted)), Some(&key))?.read_to_end(&mut plain)?;
+            return anyhow::Ok(plain);
+        };
+
+        let mut finished = Vec::new();
+        let mut writer = EncryptWriter::new(&mut finished, &key).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(decrypt(finished).unwrap(), data);
+
+        //a writer dropped before finish, e.g. on an error, must not look complete
+        for size in [0, 1000, data.len()] {
+            let mut dropped = Vec::new();
+            let mut writer = EncryptWriter::new(&mut dropped, &key).unwrap();
+            writer.write_all(&data[..size]).unwrap();
+            drop(writer);
+            assert!(decrypt(dropped).is_err());
+        }
+    }
+
+    #[test]
+    fn private_content_names() {
+        use sha2::{Digest, Sha256};
+
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>();
+
+        let password_file = testdir.tmp_instance.path().join("password");
+        std::fs::write(&password_file, "secret\n").unwrap();
+        let password = format!("--password-file={}", password_file.to_string_lossy());
+
+        //a secret stored in plain would let anyone reading the archive compute the names
+        testdir.run(&["new", "--private"]).failure();
+        testdir.run(&["new", "--private", &password]).failure();
+        assert!(!testdir.archive.join("settings.json").exists());
+
+        testdir.run(&["new", "--encrypt", "--private", &password]).success();
+        testdir.backup(&[&password]).success();
+        testdir.restore(&[&password]).success();
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+        testdir.run(&["verify", &password]).success();
+
+        let secret = std::fs::read(testdir.archive.join("secret")).unwrap();
+        assert!(hex::decode(String::from_utf8_lossy(&secret).trim()).is_err());
+
+        //the name of a content file must not be the plain hash of its data
+        let content_names = |archive: &Path| -> Vec<String> {
+            return std::fs::read_dir(archive.join("content"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .sorted()
+                .collect();
+        };
+        let plain_hash = hex::encode(Sha256::digest(std::fs::read(testdir.src.join("root.txt")).unwrap()));
+        let names = content_names(&testdir.archive);
+        assert!(!names.is_empty());
+        assert!(!names.contains(&plain_hash));
+
+        //but depend on the secret of the archive
+        let other = testdir.tmp_path();
+        run_archive(&other, &["new", "--encrypt", "--private", &password]).success();
+        run_archive(&other, &["backup", &format!("--source={}", testdir.src.to_string_lossy()), "--channel=main", &password]).success();
+        let other_names = content_names(&other);
+        assert_eq!(other_names.len(), names.len());
+        assert!(other_names.iter().all(|x| !names.contains(x)));
+    }
+
+    #[test]
+    fn hash_algorithms() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new_with(&["--hash=sha512-256"])
+            .archive_backup();
+
+        //switch the algorithm of an existing archive; older content keeps its hash
+        let settings_file = testdir.archive.join("settings.json");
+        let settings = std::fs::read_to_string(&settings_file).unwrap();
+        let mut settings: serde_json::Value = serde_json::from_str(&settings).unwrap();
+        settings["hash_algo"] = serde_json::json!("Blake3");
+        std::fs::write(&settings_file, settings.to_string()).unwrap();
+
+        std::fs::write(testdir.src.join("new_file.txt"), b"hashed with blake3").unwrap();
+
+        let testdir = testdir
+            .archive_backup()
+            .archive_restore();
+
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+        testdir.archive_verify().success();
+
+        let channel_dir = testdir.archive.join("channels").join("main");
+        let revisions: Vec<String> = std::fs::read_dir(&channel_dir)
+            .unwrap()
+            .map(|rev| std::fs::read_to_string(rev.unwrap().path()).unwrap())
+            .collect();
+        assert!(revisions.iter().any(|rev| rev.contains("hash:sha512-256:")));
+        assert!(revisions.iter().any(|rev| rev.contains("hash:blake3:")));
+    }
+
+    #[test]
+    fn incremental_backup() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        //change the content but keep size, mtime and inode; only a rehash notices that
+        let src_file = testdir.src.join("root.txt");
+        let mtime = filetime::FileTime::from_last_modification_time(&std::fs::metadata(&src_file).unwrap());
+        std::fs::write(&src_file, b"ROOT.TXT").unwrap();
+        filetime::set_file_mtime(&src_file, mtime).unwrap();
+
+        let testdir = testdir
+            .archive_backup()
+            .archive_restore();
+        assert_eq!(std::fs::read(testdir.dst.join("root.txt")).unwrap(), b"root.txt");
+
+        testdir.backup(&["--rehash"]).success();
+
+        let testdir = testdir.archive_restore();
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+    }
+
+    #[test]
+    fn chunked_files() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>();
+
+        //pseudo random data; chunk boundaries need some entropy
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut data: Vec<u8> = (0..256 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                return state as u8;
+            })
+            .collect();
+        std::fs::write(testdir.src.join("large.bin"), &data).unwrap();
+
+        let testdir = testdir
+            .archive_new_with(&["--chunking=fastcdc:4096"])
+            .archive_backup();
+        let count_before = std::fs::read_dir(testdir.archive.join("content")).unwrap().count();
+
+        //a change in the middle only adds the chunks around it
+        data[128 * 1024] ^= 0xff;
+        std::fs::write(testdir.src.join("large.bin"), &data).unwrap();
+
+        let testdir = testdir.archive_backup();
+        let count_after = std::fs::read_dir(testdir.archive.join("content")).unwrap().count();
+        assert!(count_after > count_before);
+        assert!(count_after - count_before <= 4);
+
+        let testdir = testdir.archive_restore();
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+        testdir.archive_verify().success();
+    }
+
+    #[test]
+    fn packed_content() {
+        use std::io::Write;
+
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new_with(&["--packing=packs"])
+            .archive_backup()
+            .archive_restore();
+
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+        testdir.archive_verify().success();
+
+        //only level2.info, stored twice in the source, is too large for a pack
+        let packs_dir = testdir.archive.join("packs");
+        let pack_names: Vec<String> = std::fs::read_dir(&packs_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert!(pack_names.iter().any(|name| name.ends_with(".pack")));
+        assert!(pack_names.iter().any(|name| name.ends_with(".idx")));
+        assert!(pack_names.contains(&"index".to_owned()));
+        assert_eq!(std::fs::read_dir(testdir.archive.join("content")).unwrap().count(), 1);
+
+        //gc rewrites the pack without the content of the removed file
+        std::fs::remove_file(testdir.src.join("root.txt")).unwrap();
+        let testdir = testdir.archive_backup();
+        testdir.run(&["prune", "--channel=main", "--keep-last=1", "--gc"]).success();
+
+        let index = std::fs::read_to_string(packs_dir.join("index")).unwrap();
+        assert!(!index.contains("795d0f0b"));
+
+        std::fs::remove_dir_all(&testdir.dst).unwrap();
+        let testdir = testdir.archive_restore();
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+        testdir.archive_verify().success();
+
+        //an aborted backup commits no pack; here a file changed in place after its hash was reused
+        for entry in std::fs::read_dir(&packs_dir).unwrap() {
+            std::fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+        let changed = testdir.src.join("level1/level1_1.txt");
+        let mtime = filetime::FileTime::from_last_modification_time(&std::fs::metadata(&changed).unwrap());
+        let mut data = std::fs::read(&changed).unwrap();
+        data[0] ^= 0x20;
+        std::fs::OpenOptions::new().write(true).open(&changed).unwrap().write_all(&data).unwrap();
+        filetime::set_file_mtime(&changed, mtime).unwrap();
+
+        let output = stderr(testdir.backup(&[]).failure());
+        assert!(output.contains("was changed while it was backed up"), "{}", output);
+        assert!(files_below(&packs_dir).iter().all(|x| x.starts_with(".tmp-")), "{:?}", files_below(&packs_dir));
+    }
+
+    #[test]
+    fn content_layout() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new_with(&["--layout=sharded"])
+            .archive_backup();
+
+        let content_dir = testdir.archive.join("content");
+        let top_level = |dir: &Path| -> Vec<PathBuf> {
+            return std::fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        };
+        assert!(top_level(&content_dir).iter().all(|path| path.is_dir()));
+
+        //a blob left in the flat layout, e.g. by an interrupted migration, is still found
+        let sharded = top_level(&top_level(&top_level(&content_dir)[0])[0])[0].clone();
+        std::fs::rename(&sharded, content_dir.join(sharded.file_name().unwrap())).unwrap();
+        let _ = std::fs::remove_dir(sharded.parent().unwrap());
+        let _ = std::fs::remove_dir(sharded.parent().unwrap().parent().unwrap());
+
+        let testdir = testdir.archive_restore();
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+        testdir.archive_verify().success();
+
+        testdir.run(&["migrate-layout", "--layout=flat"]).success();
+        assert!(top_level(&content_dir).iter().all(|path| path.is_file()));
+
+        testdir.run(&["migrate-layout", "--layout=sharded"]).success();
+        assert!(top_level(&content_dir).iter().all(|path| path.is_dir()));
+
+        std::fs::write(testdir.src.join("new_file.txt"), b"written sharded").unwrap();
+        std::fs::remove_dir_all(&testdir.dst).unwrap();
+        let testdir = testdir
+            .archive_backup()
+            .archive_restore();
+
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+        testdir.archive_verify().success();
+    }
+
+    #[test]
+    fn sync() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        let packed = testdir.tmp_instance.path().join("packed");
+        let blake3 = testdir.tmp_instance.path().join("blake3");
+
+        let to = |archive: &Path| format!("--to={}", archive.to_string_lossy());
+        let source = format!("--source={}", testdir.src.to_string_lossy());
+        let revisions = |archive: &Path| std::fs::read_dir(archive.join("channels").join("main")).unwrap().count();
+        let restored = |archive: &Path| {
+            let dst = testdir.tmp_path();
+            run_archive(archive, &["restore", &format!("--destination={}", dst.to_string_lossy()), "--channel=main"]).success();
+            return !dir_diff::is_different(&testdir.src, &dst).unwrap();
+        };
+
+        run_archive(&packed, &["new", "--packing=packs", "--layout=sharded"]).success();
+        run_archive(&blake3, &["new", "--hash=blake3", "--compression=zstd"]).success();
+
+        //mirror into an archive which stores content differently, but hashes it the same way
+        testdir.run(&["sync", &to(&packed), "--mode=mirror"]).success();
+        run_archive(&packed, &["verify"]).success();
+        assert!(restored(&packed));
+        assert!(packed.join("packs").join("index").is_file());
+
+        //another hash algorithm needs the content to be reencoded
+        testdir.run(&["sync", &to(&blake3)]).failure();
+        assert!(!blake3.join("channels").exists());
+        testdir.run(&["sync", &to(&blake3), "--reencode"]).success();
+        run_archive(&blake3, &["verify"]).success();
+        assert!(restored(&blake3));
+
+        //union copies the new revision back
+        std::fs::write(testdir.src.join("new_file.txt"), b"only backed up into blake3").unwrap();
+        run_archive(&blake3, &["backup", &source, "--channel=main"]).success();
+        testdir.run(&["sync", &to(&blake3), "--reencode"]).success();
+        assert_eq!(revisions(&testdir.archive), 2);
+        testdir.archive_verify().success();
+        assert!(restored(&testdir.archive));
+
+        //mirror removes revisions the source does not have, and then their content
+        std::fs::write(testdir.src.join("only_in_packed.txt"), b"only backed up into packed").unwrap();
+        run_archive(&packed, &["backup", &source, "--channel=main"]).success();
+        std::fs::remove_file(testdir.src.join("only_in_packed.txt")).unwrap();
+        assert_eq!(revisions(&packed), 2);
+        testdir.run(&["sync", &to(&packed), "--mode=mirror"]).success();
+        assert_eq!(revisions(&packed), 2);
+        assert_eq!(revisions(&testdir.archive), 2);
+        run_archive(&packed, &["verify"]).success();
+        assert!(restored(&packed));
+        let output = stdout(run_archive(&packed, &["gc", "--dry-run"]).success());
+        assert!(output.contains("; 0 unreferenced"), "{}", output);
+
+        //corrupt content is not copied
+        for entry in std::fs::read_dir(testdir.archive.join("content")).unwrap() {
+            std::fs::write(entry.unwrap().path(), b"corrupt").unwrap();
+        }
+        let fresh = testdir.tmp_instance.path().join("fresh");
+        run_archive(&fresh, &["new"]).success();
+        testdir.run(&["sync", &to(&fresh)]).failure();
+        assert!(!fresh.join("channels").exists());
+    }
+
+    #[test]
+    fn locking() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        let lock_info = |pid: u32, host: &str| {
+            return serde_json::json!({ "pid": pid, "host": host, "since": 1700000000 }).to_string();
+        };
+
+        //the lock of a process of this host which is gone is removed
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        std::fs::write(testdir.archive.join("lock"), lock_info(dead_pid, &misc_helper::hostname())).unwrap();
+        testdir.backup(&[]).success();
+        assert!(!testdir.archive.join("lock").exists());
+
+        //a lock of another host is kept until it is broken
+        std::fs::write(testdir.archive.join("lock"), lock_info(1, "elsewhere")).unwrap();
+        let output = stderr(testdir.backup(&[]).failure());
+        assert!(output.contains("locked by process 1 on elsewhere"), "{}", output);
+        testdir.restore(&[]).failure();
+        testdir.run(&["break-lock"]).success();
+        assert!(!testdir.archive.join("lock").exists());
+
+        //readers share the archive, but keep writers out
+        let locks_dir = testdir.archive.join("locks");
+        std::fs::create_dir_all(&locks_dir).unwrap();
+        std::fs::write(locks_dir.join("reader"), lock_info(std::process::id(), &misc_helper::hostname())).unwrap();
+        testdir.restore(&[]).success();
+        testdir.run(&["verify"]).success();
+        testdir.run(&["list-channel"]).success();
+        let output = stderr(testdir.backup(&[]).failure());
+        assert!(output.contains("is read by process"), "{}", output);
+        assert_eq!(std::fs::read_dir(&locks_dir).unwrap().count(), 1);
+        assert!(!testdir.archive.join("lock").exists());
+
+        //the lock of a running process is only broken with force
+        let output = stdout(testdir.run(&["break-lock"]).success());
+        assert!(output.contains("keep lock locks/reader"), "{}", output);
+        assert!(locks_dir.join("reader").exists());
+        testdir.run(&["break-lock", "--force"]).success();
+        assert!(!locks_dir.join("reader").exists());
+        testdir.backup(&[]).success();
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+    }
+
+    #[test]
+    fn atomic_revisions() {
+        use std::io::Write;
+
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        let channel_dir = testdir.archive.join("channels").join("main");
+
+        //an aborted backup leaves no revision behind
+        let missing = format!("--source={}", testdir.tmp_path().to_string_lossy());
+        testdir.run(&["backup", &missing, "--channel=main"]).failure();
+        assert_eq!(testdir.revisions().len(), 1);
+
+        //objects of a crashed writer are never read and removed by gc
+        let temp_revision = channel_dir.join(".tmp-0123456789abcdef-20990101_0000_00_0123456789abcdef");
+        let temp_content = testdir.archive.join("content").join(".tmp-0123456789abcdef-00ff");
+        std::fs::write(&temp_revision, "file:partial\n").unwrap();
+        std::fs::write(&temp_content, "partial").unwrap();
+
+        let testdir = testdir.archive_restore();
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+        testdir.archive_verify().success();
+
+        let testdir = testdir.archive_gc();
+        assert!(!temp_revision.exists());
+        assert!(!temp_content.exists());
+        assert_eq!(std::fs::read_dir(&channel_dir).unwrap().count(), 1);
+
+        //data which changed after it was hashed is never stored under the old hash;
+        //here the hash of the last revision is reused for a file changed in place
+        let root = testdir.src.join("root.txt");
+        let metadata = std::fs::metadata(&root).unwrap();
+        let mut data = std::fs::read(&root).unwrap();
+        let old_name = {
+            let mut hasher = crate::checksum::new_hasher(crate::checksum::HashAlgo::Sha256);
+            hasher.update(&data);
+            hasher.finalize().to_hex()
+        };
+        data[0] ^= 0x20;
+        std::fs::OpenOptions::new().write(true).open(&root).unwrap().write_all(&data).unwrap();
+        filetime::set_file_mtime(&root, filetime::FileTime::from_last_modification_time(&metadata)).unwrap();
+        for entry in std::fs::read_dir(testdir.archive.join("content")).unwrap() {
+            std::fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+
+        let output = stderr(testdir.backup(&[]).failure());
+        assert!(output.contains("was changed while it was backed up"), "{}", output);
+        assert_eq!(testdir.revisions().len(), 1);
+        assert!(!testdir.archive.join("content").join(old_name).exists());
+    }
+
+    #[test]
+    fn restore_corruption() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        let content_file = |data: &[u8]| {
+            let mut hasher = crate::checksum::new_hasher(crate::checksum::HashAlgo::Sha256);
+            hasher.update(data);
+            return testdir.archive.join("content").join(hasher.finalize().to_hex());
+        };
+
+        //valid content under the wrong hash only shows up when the hash is checked
+        let root = content_file(&std::fs::read(testdir.src.join("root.txt")).unwrap());
+        let other = content_file(&std::fs::read(testdir.src.join("level1/level1_1.txt")).unwrap());
+        std::fs::copy(&other, &root).unwrap();
+
+        let restore = |mode: &str| {
+            let dst = testdir.tmp_path();
+            let output = stderr(testdir.restore_to(&dst, &[&format!("--on-corruption={}", mode)]).failure());
+            assert!(output.contains("is corrupt"), "{}", output);
+            return dst;
+        };
+
+        let dst = restore("fail");
+        assert!(!dst.join("root.txt").exists());
 
+        let dst = restore("skip");
+        assert!(!dst.join("root.txt").exists());
+        assert!(dst.join("level1/level1_2.txt").is_file());
+
+        let dst = restore("keep");
+        assert_eq!(std::fs::read(dst.join("root.txt")).unwrap(), b"level1_1.txt content");
+        assert!(dst.join("level1/level1_2.txt").is_file());
+
+        //corrupt content never replaces an existing file
+        let existing = testdir.dst.join("root.txt");
+        for mode in ["fail", "skip"] {
+            std::fs::write(&existing, b"local copy").unwrap();
+            let output = stderr(testdir.restore(&[&format!("--on-corruption={}", mode)]).failure());
+            assert!(output.contains("is corrupt"), "{}", output);
+            assert_eq!(std::fs::read(&existing).unwrap(), b"local copy");
+            assert!(files_below(&testdir.dst).iter().all(|x| !x.contains(".restore-")));
+        }
+    }
+
+    #[test]
+    fn selective_restore() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        let restore = |args: &[&str]| {
+            let dst = testdir.tmp_path();
+            testdir.restore_to(&dst, args).success();
+            return files_below(&dst);
+        };
+
+        assert_eq!(restore(&["level1"]), ["level1/level1_1.txt", "level1/level1_2.txt", "level1/level2/level2.info"]);
+        assert_eq!(restore(&["--strip-components=1", "/level1/level2"]), ["level2/level2.info"]);
+        assert_eq!(restore(&["--include=*.info"]), ["duplicates/level2.info", "level1/level2/level2.info"]);
+        assert_eq!(restore(&["--include=level1/*.txt"]), ["level1/level1_1.txt", "level1/level1_2.txt"]);
+        assert_eq!(restore(&["--exclude=level2", "--exclude=duplicates", "level1", "root.txt"]), ["level1/level1_1.txt", "level1/level1_2.txt", "root.txt"]);
+        assert!(restore(&["missing"]).is_empty());
+        assert_eq!(restore(&["--include-regex=^level1/level1_[0-9]\\.txt$"]), ["level1/level1_1.txt", "level1/level1_2.txt"]);
+        assert_eq!(restore(&["--include-regex=info$", "--exclude-regex=^dup"]), ["level1/level2/level2.info"]);
+        assert_eq!(restore(&["--include=*.info", "--include-regex=^root"]), ["duplicates/level2.info", "level1/level2/level2.info", "root.txt"]);
+        testdir.restore_to(&testdir.tmp_path(), &["--include-regex=("]).failure();
+    }
+
+    #[test]
+    fn restore_conflicts() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup()
+            .archive_restore();
+
+        let restore = |mode: &str| testdir.restore(&[&format!("--on-conflict={}", mode)]);
+        let root = testdir.dst.join("root.txt");
+        let local = || std::fs::write(&root, b"local").unwrap();
+        let read = |path: &Path| std::fs::read(path).unwrap();
+
+        local();
+        restore("skip").success();
+        assert_eq!(read(&root), b"local");
+
+        restore("fail").failure();
+        assert_eq!(read(&root), b"local");
+
+        restore("rename").success();
+        restore("rename").success();
+        assert_eq!(read(&root), b"local");
+        assert_eq!(read(&testdir.dst.join("root.txt.restored")), b"root.txt");
+        assert_eq!(read(&testdir.dst.join("root.txt.restored-2")), b"root.txt");
+
+        //newer only replaces what was modified before the backed up file
+        filetime::set_file_mtime(&root, filetime::FileTime::from_unix_time(4102444800, 0)).unwrap();
+        restore("newer").success();
+        assert_eq!(read(&root), b"local");
+        filetime::set_file_mtime(&root, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+        restore("newer").success();
+        assert_eq!(read(&root), b"root.txt");
+
+        local();
+        restore("overwrite").success();
+        assert_eq!(read(&root), b"root.txt");
+    }
+
+    #[test]
+    fn mirror_restore() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup()
+            .archive_restore();
+
+        let stale_file = testdir.dst.join("level1").join("stale.txt");
+        let stale_dir = testdir.dst.join("stale").join("sub");
+        std::fs::write(&stale_file, b"stale").unwrap();
+        misc_helper::create_dir_when_missing(&stale_dir).unwrap();
+        std::fs::write(stale_dir.join("file"), b"stale").unwrap();
+        std::fs::write(testdir.dst.join("root.txt"), b"changed").unwrap();
+
+        testdir.restore(&["--dry-run"]).failure();
+        testdir.restore(&["--mirror", "--dry-run"]).success();
+        assert!(stale_file.is_file());
+        assert!(stale_dir.is_dir());
+        assert_eq!(std::fs::read(testdir.dst.join("root.txt")).unwrap(), b"changed");
+
+        testdir.restore(&["--mirror"]).success();
+        assert!(!stale_file.exists());
+        assert!(!testdir.dst.join("stale").exists());
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+
+        //a selection would remove everything outside of it
+        testdir.restore(&["--mirror", "level1"]).failure();
+        testdir.restore(&["--mirror", "--include=*.txt"]).failure();
+        testdir.restore(&["--mirror", "--exclude-regex=^level1/"]).failure();
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+
+        //what an unreadable entry would have restored is not removed
+        let revision = testdir.archive.join("channels").join("main").join(&testdir.revisions()[0]);
+        let content = std::fs::read_to_string(&revision).unwrap();
+        let root_hash = content.lines()
+            .skip_while(|x| *x != "file:root.txt")
+            .find(|x| x.starts_with("\thash:"))
+            .unwrap()
+            .to_string();
+        std::fs::write(&revision, content.replace(&root_hash, "\thash:sha256:invalid")).unwrap();
+        std::fs::write(&stale_file, b"stale").unwrap();
+
+        let output = testdir.restore(&["--mirror"]).failure().get_output().clone();
+        let (output, errors) = (String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+        assert!(errors.contains("cannot read entry root.txt"), "{}", errors);
+        assert!(output.contains("nothing removed"), "{}", output);
+        assert!(testdir.dst.join("root.txt").is_file());
+        assert!(stale_file.is_file());
+    }
+
+    #[test]
+    fn delta_restore() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup()
+            .archive_restore();
+
+        let restore = |args: &[&str]| stdout(testdir.restore(args).success());
+
+        let output = restore(&[]);
+        assert!(output.contains("unchanged \"root.txt\""), "{}", output);
+
+        //a change with the same size and mtime is only noticed when the file is hashed
+        let root = testdir.dst.join("root.txt");
+        let mtime = filetime::FileTime::from_last_modification_time(&root.metadata().unwrap());
+        std::fs::write(&root, b"ROOT.TXT").unwrap();
+        filetime::set_file_mtime(&root, mtime).unwrap();
+
+        let output = restore(&["--trust-mtime"]);
+        assert!(output.contains("unchanged \"root.txt\""), "{}", output);
+        assert_eq!(std::fs::read(&root).unwrap(), b"ROOT.TXT");
+
+        //skip is applied after the content check
+        let output = restore(&["--on-conflict=skip"]);
+        assert!(output.contains("skip \"root.txt\""), "{}", output);
+        assert!(output.contains("unchanged \"level1/level1_1.txt\""), "{}", output);
+        assert_eq!(std::fs::read(&root).unwrap(), b"ROOT.TXT");
+
+        let output = restore(&[]);
+        assert!(!output.contains("unchanged \"root.txt\""), "{}", output);
+        assert!(output.contains("unchanged \"level1/level1_1.txt\""), "{}", output);
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+    }
+
+    #[test]
+    fn diff() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        std::fs::write(testdir.src.join("added.txt"), b"added").unwrap();
+        std::fs::write(testdir.src.join("root.txt"), b"modified").unwrap();
+        std::fs::remove_file(testdir.src.join("empty_file.txt")).unwrap();
+        std::fs::rename(testdir.src.join("level1/level1_1.txt"), testdir.src.join("moved.txt")).unwrap();
+        filetime::set_file_mtime(testdir.src.join("level1/level1_2.txt"), filetime::FileTime::from_unix_time(0, 0)).unwrap();
+
+        let testdir = testdir.archive_backup();
+
+        //the names of revisions of the same second are not ordered
+        let mut revs = testdir.revisions();
+        revs.sort_by_key(|rev| std::fs::metadata(testdir.archive.join("channels").join("main").join(rev)).unwrap().modified().unwrap());
+
+        let diff = |args: &[&str]| stdout(testdir.run(&[&["diff", "--channel=main", &revs[0], &revs[1]], args].concat()).success());
+
+        let json: serde_json::Value = serde_json::from_str(&diff(&["--json"])).unwrap();
+        let changes: Vec<(String, String)> = json["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|x| x["change"] != "meta" || x["path"] == "level1/level1_2.txt")
+            .map(|x| (x["change"].as_str().unwrap().to_owned(), x["path"].as_str().unwrap().to_owned()))
+            .collect();
+        assert_eq!(changes, [
+            ("added".to_owned(), "added.txt".to_owned()),
+            ("removed".to_owned(), "empty_file.txt".to_owned()),
+            ("meta".to_owned(), "level1/level1_2.txt".to_owned()),
+            ("renamed".to_owned(), "moved.txt".to_owned()),
+            ("modified".to_owned(), "root.txt".to_owned()),
+        ]);
+        let moved = json["entries"].as_array().unwrap().iter().find(|x| x["path"] == "moved.txt").unwrap();
+        assert_eq!(moved["from"], "level1/level1_1.txt");
+        assert_eq!(json["stat"]["bytes_added"], 5 + 8);
+
+        let output = diff(&["--stat"]);
+        assert!(output.starts_with("1 added, 1 removed, 1 modified, 1 renamed"), "{}", output);
+    }
+
+    #[test]
+    fn status() {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>()
+            .archive_new()
+            .archive_backup();
+
+        let source = format!("--source={}", testdir.src.to_string_lossy());
+        let status = |args: &[&str]| testdir.run(&[&["status", &source], args].concat());
+
+        status(&["--channel=main"]).code(0);
+        status(&["--channel=missing"]).code(2);
+
+        //the same size, mtime and inode are only noticed with a rehash
+        let root = testdir.src.join("root.txt");
+        let mtime = filetime::FileTime::from_last_modification_time(&root.metadata().unwrap());
+        std::fs::write(&root, b"ROOT.TXT").unwrap();
+        filetime::set_file_mtime(&root, mtime).unwrap();
+        status(&["--channel=main"]).code(0);
+        let output = stdout(status(&["--channel=main", "--rehash"]).code(1));
+        assert!(output.contains("modified    root.txt"), "{}", output);
+
+        std::fs::write(testdir.src.join("added.txt"), b"added").unwrap();
+        std::fs::write(testdir.src.join("level1/level1_1.txt"), b"modified").unwrap();
+        std::fs::remove_file(testdir.src.join("empty_file.txt")).unwrap();
+
+        let output = stdout(status(&["--channel=main"]).code(1));
+        assert!(output.contains("new         added.txt"), "{}", output);
+        assert!(output.contains("modified    level1/level1_1.txt"), "{}", output);
+        assert!(output.contains("deleted     empty_file.txt"), "{}", output);
+        assert!(output.contains("1 new, 1 modified, 1 deleted"), "{}", output);
+
+        testdir.backup(&["--rehash"]).success();
+        status(&["--channel=main", "--rehash"]).code(0);
+    }
+
+    /// New, backup, restore and verify with the archive given as url.
+    fn backend_roundtrip(archive: &str) {
+        let testdir = TestDirs::new()
+            .unpack::<SimpleAsset>();
+
+        let archive = Path::new(archive);
+        let source = format!("--source={}", testdir.src.to_string_lossy());
+        let destination = format!("--destination={}", testdir.dst.to_string_lossy());
+
+        run_archive(archive, &["new", "--packing=packs"]).success();
+        run_archive(archive, &["new"]).failure();
+        run_archive(archive, &["backup", &source, "--channel=main"]).success();
+        run_archive(archive, &["restore", &destination, "--channel=main"]).success();
+        run_archive(archive, &["verify"]).success();
+
+        assert!(!dir_diff::is_different(&testdir.src, &testdir.dst).unwrap());
+    }
+
+    #[test]
+    fn file_backend() {
+        let tmp = TempDir::new("backup").unwrap();
+        let archive = tmp.path().join("archive");
+        backend_roundtrip(&format!("file://{}", archive.to_string_lossy()));
+
+        assert!(archive.join("settings.json").is_file());
+        assert!(!archive.join("lock").exists());
+    }
+
+    //e.g. BACKUPTOOL_TEST_S3=s3+http://localhost:9000/bucket with AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY of a MinIO
+    #[test]
+    #[ignore]
+    fn s3_backend() {
+        let url = std::env::var("BACKUPTOOL_TEST_S3").expect("BACKUPTOOL_TEST_S3 is not set");
+        backend_roundtrip(&format!("{}/test-{:016x}", url, rand::random::<u64>()));
+    }
+
+    //e.g. BACKUPTOOL_TEST_SFTP=sftp://user@localhost:2222/tmp with the host in known_hosts
+    #[test]
+    #[ignore]
+    fn sftp_backend() {
+        let url = std::env::var("BACKUPTOOL_TEST_SFTP").expect("BACKUPTOOL_TEST_SFTP is not set");
+        backend_roundtrip(&format!("{}/test-{:016x}", url, rand::random::<u64>()));
+    }
+}